@@ -1,28 +1,40 @@
 local expect = require "cc.expect"
 local expect, field = expect.expect, expect.field
 
-local block_lookup = {
-  ["minecraft:air"] = " ",
-  ["minecraft:dirt"] = "d",
-  ["minecraft:grass_block"] = "g",
-  ["minecraft:stone"] = "s",
-  ["minecraft:water"] = "w",
-}
+--[[- Fetch the blocks the server knows about, as a table mapping each block's
+Minecraft id to the character used for it in worlds.
+
+`address` is the address of the server's render websocket (as passed to
+@{display}), such as `ws://localhost:8080/render`.
+]]
+local function fetch_blocks(address)
+  expect(1, address, "string")
+
+  local url = address:gsub("^ws", "http"):gsub("/render$", "/blocks")
+  local response, err = http.get(url)
+  if not response then error(("Cannot fetch blocks from %s: %s"):format(url, err), 2) end
+
+  local blocks = textutils.unserialiseJSON(response.readAll())
+  response.close()
+  return blocks
+end
 
 --[[- Scan a portion of the world and convert in into a JSON file, which can
-then be read by our server.
+then be read by our server. `blocks` maps each block's id to its character, as
+returned by @{fetch_blocks}.
 
 We emit blocks as a `List (List String)`, such that when the JSON file is
 formatted you get a list of "slices" of the world viewed from above, from lowest
 to highest. See world.json for an example.
 ]]
-local function scan(min_x, min_y, min_z, max_x, max_y, max_z)
+local function scan(min_x, min_y, min_z, max_x, max_y, max_z, blocks)
   expect(1, min_x, "number")
   expect(2, min_y, "number")
   expect(3, min_z, "number")
   expect(4, max_x, "number")
   expect(5, max_y, "number")
   expect(6, max_z, "number")
+  expect(7, blocks, "table")
 
   min_x, max_x = math.min(min_x, max_x), math.max(min_x, max_x)
   min_y, max_y = math.min(min_y, max_y), math.max(min_y, max_y)
@@ -34,20 +46,20 @@ local function scan(min_x, min_y, min_z, max_x, max_y, max_z)
     local width = max_x - min_x + 1
     local depth = max_z - min_z + 1
 
-    local blocks = commands.getBlockInfos(min_x, y0, min_z, max_x, y0 + height - 1, max_z)
+    local infos = commands.getBlockInfos(min_x, y0, min_z, max_x, y0 + height - 1, max_z)
 
     for y = 0, height - 1 do
       local row = {}
       for z = 0, depth - 1 do
         local line = {}
         for x = 0, width - 1 do
-          local block = blocks[1 + x + z * width + y * width * depth]
+          local block = infos[1 + x + z * width + y * width * depth]
 
           if not block then
-            error(("Out of bounds at %d, %d, %d (idx=%d, len=%d)"):format(x, y, z, 1 + x + z * width + y * width * depth, #blocks))
+            error(("Out of bounds at %d, %d, %d (idx=%d, len=%d)"):format(x, y, z, 1 + x + z * width + y * width * depth, #infos))
           end
 
-          local char = block_lookup[block.name]
+          local char = blocks[block.name]
           if not char then
             print(string.format("Unknown block %s at %d, %d, %d", block.name, x, y, z))
             char = " "
@@ -66,41 +78,139 @@ local function scan(min_x, min_y, min_z, max_x, max_y, max_z)
   return output
 end
 
---- Set up a terminal redirect with our custom palette.
-local function set_palette(monitor)
+local function u16(x) return string.char(math.floor(x / 0x100) % 0x100, x % 0x100) end
+local function u32(x) return u16(math.floor(x / 0x10000)) .. u16(x % 0x10000) end
+
+--[[- Encode a world (as returned by @{scan}) in the compact binary format
+accepted by the server.
+
+This is the world's width, height and depth (as 16-bit integers), followed by a
+palette of block keys and a list of runs of blocks. Each run is two bytes: its
+length minus one, and the index of the block in the palette.
+]]
+local function encode_world(world)
+  expect(1, world, "table")
+
+  local height, depth, width = #world, #world[1], #world[1][1]
+  local palette, palette_index, runs = {}, {}, {}
+  local run_block, run_length = nil, 0
+
+  local function flush()
+    if run_length == 0 then return end
+
+    local index = palette_index[run_block]
+    if not index then
+      index = #palette
+      palette[index + 1], palette_index[run_block] = run_block, index
+    end
+
+    runs[#runs + 1] = string.char(run_length - 1, index)
+    run_length = 0
+  end
+
+  for y = 1, height do
+    for z = 1, depth do
+      local row = world[y][z]
+      for x = 1, width do
+        local block = row:sub(x, x)
+        if block ~= run_block or run_length == 256 then
+          flush()
+          run_block = block
+        end
+        run_length = run_length + 1
+      end
+    end
+  end
+  flush()
+
+  palette = table.concat(palette)
+  return u16(width) .. u16(height) .. u16(depth) .. string.char(#palette) .. palette .. table.concat(runs)
+end
+
+--- Set the palette of a monitor to the colours sent by the server.
+local function set_palette(monitor, palette)
   expect(1, monitor, "table")
+  expect(2, palette, "table")
 
-  -- White
-  monitor.setPaletteColour(2 ^ 0, 0xf0f0f0)
-  -- Green
-  monitor.setPaletteColour(2 ^ 1, 0x73b349)
-  monitor.setPaletteColour(2 ^ 2, 0x5f9f35)
-  monitor.setPaletteColour(2 ^ 3, 0x509026)
-  -- Brown
-  monitor.setPaletteColour(2 ^ 4, 0x966c4a)
-  monitor.setPaletteColour(2 ^ 5, 0x79553a)
-  monitor.setPaletteColour(2 ^ 6, 0x593d29)
-  -- Blue
-  monitor.setPaletteColour(2 ^ 7, 0x3266cc)
-  monitor.setPaletteColour(2 ^ 8, 0x4c32cc)
-  -- Cornflower Blue
-  monitor.setPaletteColour(2 ^ 9, 0x6495ED)
-  -- Grey
-  monitor.setPaletteColour(2 ^ 10, 0x8f8f8f)
-  monitor.setPaletteColour(2 ^ 11, 0x747474)
-  monitor.setPaletteColour(2 ^ 12, 0x686868)
+  for i = 1, 16 do monitor.setPaletteColour(2 ^ (i - 1), palette[i]) end
 end
 
-local function get_str(message, width, y, kind)
-  local start = kind * width + y * width * 3
-  return message:sub(start + 1, start + width)
+--- Show an error from the server on the monitor.
+local function show_error(monitor, message)
+  -- Our palette may not have been set up yet (or may not contain red/white!),
+  -- so reset it first.
+  for i = 0, 15 do monitor.setPaletteColour(2 ^ i, term.nativePaletteColour(2 ^ i)) end
+
+  monitor.setBackgroundColour(colours.black)
+  monitor.setTextColour(colours.red)
+  monitor.clear()
+  monitor.setCursorPos(2, 2)
+  monitor.write("c33d error")
+
+  monitor.setTextColour(colours.white)
+  local width = monitor.getSize()
+  local y = 3
+  for line in message:gmatch("[^\n]+") do
+    for i = 1, #line, width - 2 do
+      monitor.setCursorPos(2, y)
+      monitor.write(line:sub(i, i + width - 3))
+      y = y + 1
+    end
+  end
+end
+
+--[[- Decompress a run-length encoded frame.
+
+The frame is split into chunks, each starting with a header byte `n`. If
+`n < 128`, then the next `n + 1` bytes are copied as-is. Otherwise the next byte
+is repeated `n - 126` times.
+]]
+local function decompress(message)
+  local out, pos = {}, 1
+  while pos <= #message do
+    local header = message:byte(pos)
+    if header < 128 then
+      out[#out + 1] = message:sub(pos + 1, pos + header + 1)
+      pos = pos + header + 2
+    else
+      out[#out + 1] = message:sub(pos + 1, pos + 1):rep(header - 126)
+      pos = pos + 2
+    end
+  end
+
+  return table.concat(out)
+end
+
+--[[- Draw a frame sent by the server.
+
+Frames only contain the lines which have changed. Each line is a single byte
+containing the line's index, followed by its text, foreground and background
+colours (each `width` characters long).
+]]
+local function draw_frame(monitor, message, width)
+  local pos = 1
+  while pos <= #message do
+    local y, start = message:byte(pos), pos + 1
+    monitor.setCursorPos(2, y + 2)
+    monitor.blit(
+      message:sub(start, start + width - 1),
+      message:sub(start + width, start + 2 * width - 1),
+      message:sub(start + 2 * width, start + 3 * width - 1)
+    )
+    pos = start + 3 * width
+  end
 end
 
 local function display(args)
   expect(1, args, "table")
 
   local address = field(args, "address", "string")
-  local world = field(args, "world", "table")
+  -- Either the world to show, or the id of a world uploaded to the server with
+  -- PUT /worlds/{id} (or both, in which case the world is only sent if the
+  -- server doesn't know about that id).
+  local world = field(args, "world", "table", "nil")
+  local world_id = field(args, "world_id", "string", "nil")
+  if not world and not world_id then error("bad field 'world' (expected world or world_id)", 2) end
 
   local selector = field(args, "selector", "string", "nil") or "distance=0..16"
   local pos_command = ("data get entity @p[%s,limit=1,gamemode=!spectator] Pos"):format(selector)
@@ -110,29 +220,167 @@ local function display(args)
   local monitor_y = field(args, "monitor_y", "number")
   local monitor_z = field(args, "monitor_z", "number")
 
-  local offset_x = field(args, "offset_x", "number", "nil") or (#world[1][1] - 6) / 2
+  local offset_x = field(args, "offset_x", "number", "nil")
   local offset_y = field(args, "offset_y", "number", "nil") or 1
-  local offset_z = field(args, "offset_z", "number", "nil") or 0
+  local offset_z = field(args, "offset_z", "number", "nil")
+
+  -- One of "none", "bayer" or "floyd-steinberg".
+  local dither = field(args, "dither", "string", "nil") or "none"
+
+  -- Either "rle" or "none". Compression makes frames much smaller, but is
+  -- slightly slower to decode.
+  local compression = field(args, "compression", "string", "nil") or "rle"
+
+  -- The direction of the sun, as a table { x = ..., y = ..., z = ... } pointing
+  -- from the world towards the sun. Defaults to just south-west of overhead.
+  local sun = field(args, "sun", "table", "nil")
+
+  -- Whether blocks cast shadows. This makes rendering a little slower.
+  local shadows = field(args, "shadows", "boolean", "nil") or false
+
+  -- The time of day, in hours since midnight (so 18 is dusk). This changes the
+  -- colour of the sky. Defaults to midday.
+  local time = field(args, "time", "number", "nil")
+
+  -- Whether to draw the sun in the sky, in the direction given by `sun`.
+  local sun_disc = field(args, "sun_disc", "boolean", "nil") or false
+
+  -- Distance fog: blocks start fading into the sky `fog_start` blocks away,
+  -- and disappear entirely `fog_end` blocks away. There is no fog unless
+  -- `fog_end` is given.
+  local fog_start = field(args, "fog_start", "number", "nil")
+  local fog_end = field(args, "fog_end", "number", "nil")
+
+  -- How many blocks of a translucent block (such as water) it takes to hide
+  -- whatever is behind it. Defaults to 4.
+  local translucent_depth = field(args, "translucent_depth", "number", "nil")
+
+  -- Either "binary" or "json". The binary format is much smaller and faster to
+  -- encode, so JSON is only useful when debugging.
+  local world_format = field(args, "world_format", "string", "nil") or "binary"
+
+  -- Monitors expose which way they face in their block state, so read it from
+  -- there if not given explicitly.
+  local facing = field(args, "facing", "string", "nil")
+  if not facing then
+    local info = commands.getBlockInfo(monitor_x, monitor_y, monitor_z)
+    facing = info and info.state and info.state.facing or "north"
+  end
+
+  -- By default, the monitor sits on the edge of the world nearest the player,
+  -- centred along it. This depends on which way the monitor faces, and so needs
+  -- the world's size for anything but north.
+  if not offset_x or not offset_z then
+    if not world and facing ~= "north" then
+      error("bad field 'offset_x'/'offset_z' (required for a world_id facing " .. facing .. ")", 2)
+    end
+
+    local default_x, default_z = 0, 0
+    if world then
+      local world_width, world_depth = #world[1][1], #world[1]
+      if facing == "south" then
+        default_x, default_z = world_width - (world_width - 6) / 2 - 1, world_depth - 1
+      elseif facing == "east" then
+        default_x, default_z = world_width - 1, (world_depth - 6) / 2
+      elseif facing == "west" then
+        default_x, default_z = 0, world_depth - (world_depth - 6) / 2 - 1
+      else
+        default_x = (world_width - 6) / 2
+      end
+    end
+    offset_x, offset_z = offset_x or default_x, offset_z or default_z
+  end
 
   monitor.setTextScale(0.5)
-  set_palette(monitor)
-  monitor.setBackgroundColour(colours.black)
-  monitor.clear()
 
   local width, height = monitor.getSize()
+
+  -- Monitors don't expose their size in blocks, so work backwards from the size
+  -- of the terminal at a text scale of 0.5.
+  local physical_width = field(args, "physical_width", "number", "nil") or math.floor(width * 3 / 64 + 0.3125 + 0.5)
+  local physical_height = field(args, "physical_height", "number", "nil") or math.floor(height * 4.5 / 64 + 0.3125 + 0.5)
+
   width, height = width - 2, height - 2
 
-  local initial_payload = textutils.serializeJSON({
-    world = world,
+  local header = {
+    version = 1,
+    worldId = world_id,
     offsetX = offset_x, offsetY = offset_y, offsetZ = offset_z,
-  })
+    facing = facing,
+    width = width, height = height,
+    physicalWidth = physical_width, physicalHeight = physical_height,
+    dither = dither,
+    compression = compression,
+    sun = sun,
+    shadows = shadows,
+    translucentDepth = translucent_depth,
+    time = time,
+    sunDisc = sun_disc,
+    fogStart = fog_start, fogEnd = fog_end,
+  }
+
+  -- Send the header and the whole world. Binary messages are the JSON header
+  -- (prefixed with its length), followed by the encoded world.
+  local function send_world(ws)
+    if world and world_format == "binary" then
+      local json = textutils.serializeJSON(header)
+      ws.send(u32(#json) .. json .. encode_world(world), true)
+    else
+      header.world = world
+      ws.send(textutils.serializeJSON(header), false)
+      header.world = nil
+    end
+  end
+
+  -- Apply a patch to our copy of the world, so that the world we send after
+  -- reconnecting includes it. Like the server, patches which don't fit inside
+  -- the world are ignored.
+  local copied = false
+  local function apply_edits(edits)
+    if not world then return end
+
+    local height, depth, width = #world, #world[1], #world[1][1]
+    for _, edit in ipairs(edits) do
+      local region = edit.world or { { edit.block } }
+      if edit.x < 0 or edit.y < 0 or edit.z < 0
+        or edit.x + #region[1][1] > width or edit.y + #region > height or edit.z + #region[1] > depth then
+        return
+      end
+    end
+
+    -- Don't change the caller's table.
+    if not copied then
+      local copy = {}
+      for y, layer in ipairs(world) do copy[y] = { table.unpack(layer) } end
+      world, copied = copy, true
+    end
+
+    for _, edit in ipairs(edits) do
+      for dy, layer in ipairs(edit.world or { { edit.block } }) do
+        for dz, row in ipairs(layer) do
+          local old = world[edit.y + dy][edit.z + dz]
+          world[edit.y + dy][edit.z + dz] = old:sub(1, edit.x) .. row .. old:sub(edit.x + #row + 1)
+        end
+      end
+    end
+  end
 
   local ws = assert(http.websocket(address))
-  ws.send(initial_payload)
+  send_world(ws)
 
   local has_position, player_x, player_y, player_z = false, 0, 0, 0
   local locate_task = nil
 
+  -- Errors in our initial message (i.e. before we receive the palette) are
+  -- fatal, and reconnecting would just fail again.
+  local connected, fatal_error = false, nil
+
+  -- The id the server gave our world. When reconnecting, we send this rather
+  -- than the whole world, as the server's world already has our patches. If
+  -- the server has forgotten it, we send our (patched) copy of the world again
+  -- instead.
+  local known_id, resuming = nil, false
+
   local running = true
   while running do
     if locate_task == nil then
@@ -166,15 +414,51 @@ local function display(args)
         has_position = false
       end
 
+    elseif event == "c33d_patch" then
+      -- Other programs can edit the world with os.queueEvent("c33d_patch", edits),
+      -- where each edit is either { x, y, z, block } or { x, y, z, world }.
+      apply_edits(arg1)
+      if ws then ws.send(textutils.serializeJSON({ type = "worldPatch", edits = arg1 })) end
+
+    elseif event == "websocket_message" and arg1 == address and not arg3 then
+      -- Text messages are JSON, used for everything apart from frames.
+      local message = textutils.unserialiseJSON(arg2)
+      if message and message.type == "world" then
+        known_id = message.id
+      elseif message and message.type == "palette" then
+        -- The first palette of a connection means a new session, which needs
+        -- our position before it can draw anything. We only send it when the
+        -- player moves, so send it again now.
+        if not connected and has_position then
+          ws.send(textutils.serializeJSON({ x = player_x, y = player_y, z = player_z }))
+        end
+
+        connected, resuming = true, false
+        set_palette(monitor, message.colours)
+        monitor.setBackgroundColour(colours.black)
+        monitor.clear()
+      elseif message and message.type == "error" and resuming and message.code == "unknown_world" then
+        -- The server will close the connection, and we'll send the whole world
+        -- when reconnecting.
+        known_id, resuming = nil, false
+      elseif message and message.type == "error" then
+        local err = ("%s: %s"):format(message.code, message.message)
+        printError(err)
+        if not connected then
+          fatal_error = err
+          show_error(monitor, err)
+        end
+      end
+
     elseif event == "websocket_message" and arg1 == address then
       local message = arg2
-      for y = 0, height - 1 do
-        monitor.setCursorPos(2, y + 2)
-        monitor.blit(get_str(message, width, y, 0), get_str(message, width, y, 1), get_str(message, width, y, 2))
-      end
+      if compression == "rle" then message = decompress(message) end
+      draw_frame(monitor, message, width)
 
     elseif event == "websocket_closed" and arg1 == address then
       locate_task = nil
+      if fatal_error then error(fatal_error, 0) end
+      connected = false
 
       printError("Connection lost")
       repeat
@@ -186,7 +470,15 @@ local function display(args)
       until ws
 
       print("Reconnected")
-      ws.send(initial_payload)
+      if known_id then
+        local resume = {}
+        for k, v in pairs(header) do resume[k] = v end
+        resume.world, resume.worldId = nil, known_id
+        ws.send(textutils.serializeJSON(resume))
+        resuming = true
+      else
+        send_world(ws)
+      end
     end
   end
 
@@ -201,7 +493,9 @@ if select('#', ...) == 1 then
   local caller = debug.getinfo(2)
   if caller and caller.name == "require" and caller.short_src == "require.lua" then
     return {
+      fetch_blocks = fetch_blocks,
       scan = scan,
+      encode_world = encode_world,
       set_palette = set_palette,
       display = display,
     }
@@ -217,19 +511,20 @@ local function check_num(name, value)
 end
 
 if ... == "scan" then
-  if select('#', ...) ~= 8 then
-    printError("Usage: c33d scan MIN-X MIN-Y MIN-Z MAX-X MAX-Y MAX-Z OUTPUT")
+  if select('#', ...) ~= 9 then
+    printError("Usage: c33d scan SERVER MIN-X MIN-Y MIN-Z MAX-X MAX-Y MAX-Z OUTPUT")
     error()
   end
 
-  local _, min_x, min_y, min_z, max_x, max_y, max_z, filename = ...
+  local _, address, min_x, min_y, min_z, max_x, max_y, max_z, filename = ...
   local output = scan(
     check_num("MIN-X", min_x),
     check_num("MIN-Y", min_y),
     check_num("MIN-Z", min_z),
     check_num("MAX-X", max_x),
     check_num("MAX-Y", max_y),
-    check_num("MAX-Z", max_z)
+    check_num("MAX-Z", max_z),
+    fetch_blocks(address)
   )
 
   local handle = assert(fs.open(shell.resolve(filename), "w"))
@@ -265,7 +560,7 @@ elseif ... == "draw" then
 else
   -- Unknown command
   printError("Usage:")
-  printError("  c33d scan MIN-X MIN-Y MIN-Z MAX-X MAX-Y MAX-Z OUTPUT")
+  printError("  c33d scan SERVER MIN-X MIN-Y MIN-Z MAX-X MAX-Y MAX-Z OUTPUT")
   printError("  c33d draw SERVER MONITOR MON-X MON-Y MON-Z WORLD [WORLD-X WORLD-Y WORLD-Z]")
   error()
 end
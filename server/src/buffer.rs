@@ -1,15 +1,12 @@
-// Monitors are 164 wide and 81 high. We want to avoid rendering on the edge as
-// teletext characters are weird, so minus two on either side.
-pub const MON_WIDTH: u32 = 164 - 2;
-pub const MON_HEIGHT: u32 = 81 - 2;
-
-pub const BUF_WIDTH: u32 = MON_WIDTH * 2;
-pub const BUF_HEIGHT: u32 = MON_HEIGHT * 3;
-
-const SIZE: usize = (BUF_WIDTH * BUF_HEIGHT) as usize;
+// Monitors are at most 164 wide and 81 high. We want to avoid rendering on the
+// edge as teletext characters are weird, so minus two on either side.
+pub const MAX_WIDTH: u32 = 164 - 2;
+pub const MAX_HEIGHT: u32 = 81 - 2;
 
 const HEX_COLOURS: &[u8] = "0123456789abcdef".as_bytes();
 
+use crate::palette::{Palette, Rgb};
+
 pub type Colour = u8;
 
 fn to_hex(colour: Colour) -> u8 {
@@ -17,29 +14,100 @@ fn to_hex(colour: Colour) -> u8 {
   HEX_COLOURS[colour as usize]
 }
 
+/// A grid of full-colour pixels, as produced by [`crate::ray::render`]. This is
+/// then reduced to a [`Buffer`] of palette colours by
+/// [`crate::dither::quantise`].
+pub struct Frame {
+  pub width: u32,
+  pub height: u32,
+  pixels: Vec<Rgb>,
+}
+
+impl Frame {
+  /// Create a new frame, with the same dimensions as [`Buffer::new`].
+  pub fn new(width: u32, height: u32) -> Frame {
+    let (width, height) = (width * 2, height * 3);
+    Frame { width, height, pixels: vec![Rgb::new(0.0, 0.0, 0.0); (width * height) as usize] }
+  }
+
+  pub fn get(&self, x: u32, y: u32) -> Rgb {
+    self.pixels[(x + y * self.width) as usize]
+  }
+
+  pub fn as_mut_slice(&mut self) -> &mut [Rgb] {
+    self.pixels.as_mut_slice()
+  }
+}
+
 /// A mutable grid of pixels (each pixel being one of CC's 16 colours), which
 /// can be 'drawn' to a terminal or monitor.
 ///
-/// Buffers are (at least currently) a constant size (see [`BUF_WIDTH`],
-/// [`BUF_HEIGHT`]), suitable for rendering to a max-size monitor with 1px
-/// margin.
+/// Each terminal character is drawn as a 2x3 block of pixels, so the buffer's
+/// [`width`](Buffer::width) and [`height`](Buffer::height) are twice and three
+/// times the size of the terminal respectively.
 pub struct Buffer {
-  colours: [Colour; SIZE],
+  pub width: u32,
+  pub height: u32,
+  colours: Vec<Colour>,
 }
 
 impl Buffer {
-  pub fn new() -> Buffer {
-    Buffer { colours: [0; SIZE] }
+  /// Create a new buffer, suitable for drawing to a terminal `width` characters
+  /// wide and `height` characters high.
+  pub fn new(width: u32, height: u32) -> Buffer {
+    let (width, height) = (width * 2, height * 3);
+    Buffer { width, height, colours: vec![0; (width * height) as usize] }
   }
 
   fn get(&self, x: u32, y: u32) -> Colour {
-    self.colours[(x + y * BUF_WIDTH) as usize]
+    self.colours[(x + y * self.width) as usize]
+  }
+
+  pub fn set(&mut self, x: u32, y: u32, colour: Colour) {
+    self.colours[(x + y * self.width) as usize] = colour;
   }
 
   pub fn as_mut_slice(&mut self) -> &mut [Colour] {
     self.colours.as_mut_slice()
   }
 
+  /// Pick the pair of colours which best represent a 2x3 cell, returning them
+  /// in order of how common they are. Every pixel will be drawn with whichever
+  /// of the two is perceptually closest, so we choose the pair which minimises
+  /// the total distance.
+  fn best_pair(cell: &[Colour; 6], palette: &Palette) -> (Colour, Colour) {
+    let mut totals = [0; 16];
+    for colour in cell {
+      totals[*colour as usize] += 1;
+    }
+
+    let mut colours: [Colour; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+    colours.sort_by_key(|k| -totals[*k as usize]);
+    let unique = totals.iter().filter(|x| **x > 0).count();
+    let colours = &colours[..unique.max(2)];
+
+    // Consider the most common colours first, so that ties are broken in favour
+    // of the pair which is drawn exactly most often.
+    let mut best = (colours[0], colours[1], f32::MAX);
+    for (i, first) in colours.iter().enumerate() {
+      for second in &colours[i + 1..] {
+        let error: f32 = cell
+          .iter()
+          .map(|c| {
+            palette
+              .distance(*c, *first)
+              .min(palette.distance(*c, *second))
+          })
+          .sum();
+        if error < best.2 {
+          best = (*first, *second, error);
+        }
+      }
+    }
+
+    (best.0, best.1)
+  }
+
   /// 'Draw' the buffer.
   ///
   /// This converts it to a single string containing the whole terminal contents
@@ -48,59 +116,45 @@ impl Buffer {
   /// calls).
   ///
   /// This uses CC's teletext characters to approximate the actual buffer's
-  /// contents. If there are more than 2 colours in each 2x3 region, only the
-  /// two most common will be used.
-  pub fn draw(&self) -> Vec<u8> {
-    let mut vec = vec![0; (MON_WIDTH * MON_HEIGHT * 3) as usize];
-
-    for mon_y in 0..MON_HEIGHT {
+  /// contents. Each 2x3 region can only contain two colours, so if there are
+  /// more we pick the pair which best represents the region and replace every
+  /// other colour with the closest of the two.
+  pub fn draw(&self, palette: &Palette) -> Vec<u8> {
+    let (mon_width, mon_height) = (self.width / 2, self.height / 3);
+    let mut vec = vec![0; (mon_width * mon_height * 3) as usize];
+
+    for mon_y in 0..mon_height {
       let y = mon_y * 3;
 
-      for mon_x in 0..MON_WIDTH {
+      for mon_x in 0..mon_width {
         let x = mon_x * 2;
 
-        // I wish we had dependent types (or at least Ada-style arrays). Alas.
-        let mut totals = [0; 16];
-        let mut unique = 0;
-        for dx in 0..2 {
-          for dy in 0..3 {
-            let colour = self.get(x + dx, y + dy) as usize;
-            if totals[colour] == 0 {
-              unique += 1;
-            }
-            totals[colour] += 1;
+        // Pixels are ordered by row, so the bottom right pixel (which teletext
+        // characters always draw with the background colour) is last.
+        let mut cell = [0; 6];
+        for dy in 0..3 {
+          for dx in 0..2 {
+            cell[(dx + dy * 2) as usize] = self.get(x + dx, y + dy);
           }
         }
 
-        let (text, fg, bg) = if unique == 1 {
-          let (mut colour, mut best) = (0, 0);
-          for (i, x) in totals.into_iter().enumerate() {
-            if x > best {
-              colour = i;
-              best = x;
-            }
-          }
-
-          (b' ', 0_u8, colour as u8)
+        let (text, fg, bg) = if cell.iter().all(|c| *c == cell[0]) {
+          (b' ', 0_u8, cell[0])
         } else {
-          let mut colours: [Colour; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
-          colours.sort_by_key(|k| -totals[*k as usize]);
-
-          // TODO: blittle-like colour similarity?
-          let bg = colours[0];
-          let fg = colours[1];
-          let last = if self.get(x + 1, y + 2) == fg { fg } else { bg };
+          let (bg, fg) = Buffer::best_pair(&cell, palette);
+          let nearest = |c: Colour| {
+            if palette.distance(c, fg) < palette.distance(c, bg) {
+              fg
+            } else {
+              bg
+            }
+          };
 
+          let last = nearest(cell[5]);
           let mut code: u8 = 128;
-          for dx in 0..2 {
-            for dy in 0..3 {
-              if dx == 1 && dy == 2 {
-                continue;
-              }
-
-              if self.get(x + dx, y + dy) != last {
-                code |= 1 << (2 * dy + dx);
-              }
+          for (i, colour) in cell[..5].iter().enumerate() {
+            if nearest(*colour) != last {
+              code |= 1 << i;
             }
           }
 
@@ -111,12 +165,34 @@ impl Buffer {
           }
         };
 
-        vec[(mon_y * MON_WIDTH * 3 + mon_x) as usize] = text;
-        vec[(mon_y * MON_WIDTH * 3 + MON_WIDTH + mon_x) as usize] = to_hex(fg);
-        vec[(mon_y * MON_WIDTH * 3 + 2 * MON_WIDTH + mon_x) as usize] = to_hex(bg);
+        vec[(mon_y * mon_width * 3 + mon_x) as usize] = text;
+        vec[(mon_y * mon_width * 3 + mon_width + mon_x) as usize] = to_hex(fg);
+        vec[(mon_y * mon_width * 3 + 2 * mon_width + mon_x) as usize] = to_hex(bg);
       }
     }
 
     vec
   }
 }
+
+/// Encode the lines of a drawn buffer (see [`Buffer::draw`]) which have changed
+/// since the previous frame. If there is no previous frame, every line is
+/// included.
+///
+/// Each changed line is written as a single byte containing its (0-based) index,
+/// followed by the line's text, foreground and background colours, exactly as
+/// in [`Buffer::draw`]. If nothing has changed, this is empty.
+pub fn delta(previous: Option<&[u8]>, current: &[u8], width: u32) -> Vec<u8> {
+  let line_size = (width * 3) as usize;
+  let mut out = Vec::new();
+  for (y, line) in current.chunks_exact(line_size).enumerate() {
+    let unchanged =
+      previous.is_some_and(|previous| &previous[y * line_size..(y + 1) * line_size] == line);
+    if !unchanged {
+      out.push(y as u8);
+      out.extend_from_slice(line);
+    }
+  }
+
+  out
+}
@@ -0,0 +1,200 @@
+//! A cache of worlds, shared between every session showing them.
+
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::watch;
+
+use crate::world::{Blocks, Edit, World, WorldError};
+
+/// A world which may be shown by several sessions at once. Sessions subscribe
+/// to it, and are notified whenever it is replaced or edited.
+pub struct SharedWorld {
+  world: watch::Sender<Arc<World>>,
+  /// Held while modifying the world, so concurrent edits aren't lost.
+  lock: Mutex<()>,
+  /// Whether this world was uploaded explicitly, and so should be kept even
+  /// when no sessions are showing it.
+  pinned: AtomicBool,
+  /// Whether this world's id is the hash of its contents (see [`content_id`]),
+  /// and so it must never change.
+  content_addressed: AtomicBool,
+}
+
+impl SharedWorld {
+  fn new(world: World, pinned: bool, content_addressed: bool) -> SharedWorld {
+    let (world, _) = watch::channel(Arc::new(world));
+    SharedWorld {
+      world,
+      lock: Mutex::new(()),
+      pinned: AtomicBool::new(pinned),
+      content_addressed: AtomicBool::new(content_addressed),
+    }
+  }
+
+  /// Subscribe to this world. The receiver always holds the latest version of
+  /// the world, and is notified when it changes.
+  pub fn subscribe(&self) -> watch::Receiver<Arc<World>> {
+    self.world.subscribe()
+  }
+
+  /// Replace this world entirely.
+  fn set(&self, world: World) {
+    let _guard = self.lock.lock().unwrap();
+    self.world.send_replace(Arc::new(world));
+  }
+
+  /// Apply a list of edits to this world (see [`World::apply`]). This should
+  /// not be used on content-addressed worlds (see [`WorldCache::apply`]).
+  ///
+  /// Sessions may still be rendering the old world, so this edits a copy of it.
+  /// Copies share any sections which weren't edited, so this is cheap for
+  /// small edits. Large edits may take a while, so this shouldn't be called on
+  /// the async worker threads.
+  pub fn apply(&self, edits: &[Edit], blocks: &Blocks) -> Result<(), WorldError> {
+    let _guard = self.lock.lock().unwrap();
+    let mut world = World::clone(&self.world.borrow());
+    world.apply(edits, blocks)?;
+    self.world.send_replace(Arc::new(world));
+    Ok(())
+  }
+}
+
+/// The prefix of ids computed with [`content_id`], and of copies made from
+/// those worlds. Worlds may not be uploaded with ids in this prefix, so that a
+/// content id always refers to the world it was computed from.
+const CONTENT_ID_PREFIX: &str = "sha1:";
+
+/// Compute the id of a world uploaded in a session's initial message. This is
+/// the SHA-1 hash of its serialised form, so identical worlds are only stored
+/// once.
+pub fn content_id(world: &[u8]) -> String {
+  let hash = Sha1::digest(world);
+  let hex: String = hash.iter().map(|byte| format!("{:02x}", byte)).collect();
+  format!("{}{}", CONTENT_ID_PREFIX, hex)
+}
+
+/// Whether this id is reserved for content-addressed worlds (see
+/// [`content_id`]).
+pub fn is_content_id(id: &str) -> bool {
+  id.starts_with(CONTENT_ID_PREFIX)
+}
+
+/// All worlds currently known to the server, by id.
+///
+/// Worlds uploaded with `PUT /worlds/{id}` are kept until the server stops.
+/// Any other world is removed once the last session showing it disconnects.
+pub struct WorldCache {
+  worlds: RwLock<HashMap<String, Arc<SharedWorld>>>,
+  max_volume: usize,
+  /// The number of content-addressed worlds which have been copied to a new id
+  /// (see [`WorldCache::apply`]), used to pick those ids.
+  copies: AtomicUsize,
+}
+
+type Subscription = (Arc<SharedWorld>, watch::Receiver<Arc<World>>);
+
+impl WorldCache {
+  pub fn new(max_volume: usize) -> WorldCache {
+    WorldCache { worlds: RwLock::new(HashMap::new()), max_volume, copies: AtomicUsize::new(0) }
+  }
+
+  /// The maximum number of blocks a world may contain.
+  pub fn max_volume(&self) -> usize {
+    self.max_volume
+  }
+
+  /// Upload a world, replacing any existing world with the same id. Sessions
+  /// showing the old world will switch to the new one.
+  ///
+  /// The id must not be a content id (see [`is_content_id`]).
+  pub fn put(&self, id: String, world: World) {
+    debug_assert!(!is_content_id(&id));
+    let mut worlds = self.worlds.write().unwrap();
+    match worlds.get(&id) {
+      Some(existing) => {
+        existing.pinned.store(true, Ordering::Relaxed);
+        existing.set(world);
+      }
+      None => {
+        worlds.insert(id, Arc::new(SharedWorld::new(world, true, false)));
+      }
+    }
+  }
+
+  /// Get the world with the given id and subscribe to it, if it exists.
+  pub fn subscribe(&self, id: &str) -> Option<Subscription> {
+    let worlds = self.worlds.read().unwrap();
+    let shared = worlds.get(id)?.clone();
+    let receiver = shared.subscribe();
+    Some((shared, receiver))
+  }
+
+  /// Add a world sent by a session, and subscribe to it. `content_addressed`
+  /// should be set if `id` was computed with [`content_id`].
+  ///
+  /// Worlds should be decoded before calling this, so that we don't hold the
+  /// lock while doing so. This means another session may have added a world
+  /// with the same id in the meantime, in which case we use that one instead.
+  pub fn insert(&self, id: &str, world: World, content_addressed: bool) -> Subscription {
+    let mut worlds = self.worlds.write().unwrap();
+    let shared = worlds
+      .entry(id.to_string())
+      .or_insert_with(|| Arc::new(SharedWorld::new(world, false, content_addressed)))
+      .clone();
+    let receiver = shared.subscribe();
+    (shared, receiver)
+  }
+
+  /// Apply a list of edits to the world with the given id, which a session is
+  /// subscribed to.
+  ///
+  /// Content-addressed worlds must always match their id, so these are never
+  /// edited. Instead, we edit a copy of the world and add it under a new id,
+  /// returning that id and a subscription to it. The session should switch to
+  /// this world and release the old one.
+  ///
+  /// Like [`SharedWorld::apply`], this shouldn't be called on the async worker
+  /// threads.
+  pub fn apply(
+    &self,
+    id: &str,
+    shared: &SharedWorld,
+    edits: &[Edit],
+    blocks: &Blocks,
+  ) -> Result<Option<(String, Subscription)>, WorldError> {
+    if !shared.content_addressed.load(Ordering::Relaxed) {
+      shared.apply(edits, blocks)?;
+      return Ok(None);
+    }
+
+    let mut world = World::clone(&shared.world.borrow());
+    world.apply(edits, blocks)?;
+
+    let shared = Arc::new(SharedWorld::new(world, false, false));
+    let mut worlds = self.worlds.write().unwrap();
+    let id = loop {
+      let copy = self.copies.fetch_add(1, Ordering::Relaxed);
+      let new_id = format!("{}-{}", id, copy);
+      if !worlds.contains_key(&new_id) {
+        break new_id;
+      }
+    };
+    worlds.insert(id.clone(), shared.clone());
+
+    let receiver = shared.subscribe();
+    Ok(Some((id, (shared, receiver))))
+  }
+
+  /// Remove a world if it is no longer used. This should be called after a
+  /// session has dropped its receiver.
+  pub fn release(&self, id: &str) {
+    let mut worlds = self.worlds.write().unwrap();
+    if let Some(shared) = worlds.get(id) {
+      if !shared.pinned.load(Ordering::Relaxed) && shared.world.receiver_count() == 0 {
+        worlds.remove(id);
+      }
+    }
+  }
+}
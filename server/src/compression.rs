@@ -0,0 +1,70 @@
+//! Optional compression of the frames sent to the client.
+
+use serde::Deserialize;
+
+/// How frames should be compressed before being sent. This is negotiated when
+/// the client connects.
+#[derive(Copy, Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Compression {
+  /// Frames are sent as-is.
+  #[default]
+  None,
+  /// Frames are run-length encoded (see [`rle`]).
+  Rle,
+}
+
+impl Compression {
+  pub fn compress(self, data: Vec<u8>) -> Vec<u8> {
+    match self {
+      Compression::None => data,
+      Compression::Rle => rle(&data),
+    }
+  }
+}
+
+/// The longest run of repeated bytes we can encode.
+const MAX_RUN: usize = 129;
+
+/// The longest run of literal bytes we can encode.
+const MAX_LITERAL: usize = 128;
+
+fn push_literal(out: &mut Vec<u8>, literal: &[u8]) {
+  for chunk in literal.chunks(MAX_LITERAL) {
+    out.push((chunk.len() - 1) as u8);
+    out.extend_from_slice(chunk);
+  }
+}
+
+/// Run-length encode some data, in a format which is cheap to decode in Lua.
+///
+/// The data is split into chunks, each starting with a header byte `n`:
+///  - If `n < 128`, then the next `n + 1` bytes should be copied as-is.
+///  - Otherwise, the next byte should be repeated `n - 126` times.
+///
+/// Frames contain long runs of the same colour, but the teletext characters
+/// rarely repeat, so this avoids doubling the size of lines with lots of detail.
+pub fn rle(data: &[u8]) -> Vec<u8> {
+  let mut out = Vec::with_capacity(data.len() / 2);
+  let mut literal_start = 0;
+  let mut i = 0;
+  while i < data.len() {
+    let run = data[i..]
+      .iter()
+      .take(MAX_RUN)
+      .take_while(|x| **x == data[i])
+      .count();
+    if run >= 3 {
+      push_literal(&mut out, &data[literal_start..i]);
+      out.push((run + 126) as u8);
+      out.push(data[i]);
+      i += run;
+      literal_start = i;
+    } else {
+      i += 1;
+    }
+  }
+
+  push_literal(&mut out, &data[literal_start..]);
+  out
+}
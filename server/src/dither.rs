@@ -0,0 +1,196 @@
+//! Reduces a full-colour [`Frame`] to a [`Buffer`] of palette colours,
+//! optionally dithering it to hide banding in gradients.
+//!
+//! Each 2x3 cell of the buffer is drawn as a single teletext character, and so
+//! can only contain two colours. Both dithering modes pick the best pair of
+//! colours for each cell up front, and then dither between them. This means
+//! [`Buffer::draw`] never has to throw away any detail we've added.
+
+use rayon::prelude::*;
+use serde::Deserialize;
+
+use crate::buffer::{Buffer, Colour, Frame};
+use crate::palette::{Palette, Rgb};
+
+/// How a frame should be dithered.
+#[derive(Copy, Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Dither {
+  /// Map each pixel to the nearest palette colour.
+  #[default]
+  None,
+  /// Ordered dithering, using a threshold matrix the size of a single cell.
+  Bayer,
+  /// Floyd–Steinberg error diffusion.
+  FloydSteinberg,
+}
+
+/// The threshold matrix for ordered dithering. This covers exactly one 2x3 cell,
+/// with neighbouring thresholds as far apart as possible.
+const BAYER: [[f32; 2]; 3] = [[0.0, 3.0], [4.0, 1.0], [2.0, 5.0]];
+
+/// How far ordered dithering may nudge each channel of a colour.
+const BAYER_SPREAD: f32 = 32.0;
+
+/// Pick the two colours which best represent a cell. The candidates are the
+/// nearest palette colours to each pixel, and we choose the pair which
+/// minimises the total (perceptual) error.
+fn cell_pair(cell: &[Rgb; 6], palette: &Palette) -> (Colour, Colour) {
+  let mut candidates: Vec<Colour> = cell.iter().map(|c| palette.nearest(*c)).collect();
+  candidates.sort_unstable();
+  candidates.dedup();
+
+  if candidates.len() == 1 {
+    return (candidates[0], candidates[0]);
+  }
+
+  let mut best = (candidates[0], candidates[1], f32::MAX);
+  for (i, first) in candidates.iter().enumerate() {
+    for second in &candidates[i + 1..] {
+      let (a, b) = (palette.get(*first), palette.get(*second));
+      let error: f32 = cell.iter().map(|c| c.distance(a).min(c.distance(b))).sum();
+      if error < best.2 {
+        best = (*first, *second, error);
+      }
+    }
+  }
+
+  (best.0, best.1)
+}
+
+/// Find which of a pair of colours is closest.
+fn nearest_of(colour: Rgb, (a, b): (Colour, Colour), palette: &Palette) -> Colour {
+  if colour.distance(palette.get(a)) <= colour.distance(palette.get(b)) {
+    a
+  } else {
+    b
+  }
+}
+
+fn quantise_nearest(frame: &Frame, palette: &Palette) -> Buffer {
+  let mut buffer = Buffer::new(frame.width / 2, frame.height / 3);
+  buffer
+    .as_mut_slice()
+    .par_chunks_exact_mut(frame.width as usize)
+    .enumerate()
+    .for_each(|(y, out)| {
+      for (x, colour) in out.iter_mut().enumerate() {
+        *colour = palette.nearest(frame.get(x as u32, y as u32));
+      }
+    });
+  buffer
+}
+
+fn quantise_bayer(frame: &Frame, palette: &Palette) -> Buffer {
+  let mut buffer = Buffer::new(frame.width / 2, frame.height / 3);
+  for cell_y in 0..frame.height / 3 {
+    for cell_x in 0..frame.width / 2 {
+      let mut cell = [Rgb::new(0.0, 0.0, 0.0); 6];
+      for dy in 0..3 {
+        for dx in 0..2 {
+          let threshold = (BAYER[dy][dx] + 0.5) / 6.0 - 0.5;
+          let colour = frame.get(cell_x * 2 + dx as u32, cell_y * 3 + dy as u32);
+          cell[dx + dy * 2] =
+            (colour + Rgb::new(1.0, 1.0, 1.0) * (threshold * BAYER_SPREAD)).clamp();
+        }
+      }
+
+      let pair = cell_pair(&cell, palette);
+      for (i, colour) in cell.iter().enumerate() {
+        buffer.set(
+          cell_x * 2 + (i % 2) as u32,
+          cell_y * 3 + (i / 2) as u32,
+          nearest_of(*colour, pair, palette),
+        );
+      }
+    }
+  }
+  buffer
+}
+
+fn quantise_floyd_steinberg(frame: &Frame, palette: &Palette) -> Buffer {
+  let (width, height) = (frame.width as usize, frame.height as usize);
+  let mut buffer = Buffer::new(frame.width / 2, frame.height / 3);
+  let mut error = vec![Rgb::new(0.0, 0.0, 0.0); width * height];
+  let mut done = vec![false; width * height];
+
+  // We process the frame a cell at a time rather than a row at a time, so the
+  // pixel below and to the left may already have been drawn. In that case its
+  // share of the error is pushed to the pixel directly below instead (which is
+  // always either in this cell or the next row of cells).
+  for cell_y in 0..height / 3 {
+    for cell_x in 0..width / 2 {
+      let mut cell = [Rgb::new(0.0, 0.0, 0.0); 6];
+      for (i, colour) in cell.iter_mut().enumerate() {
+        let (x, y) = (cell_x * 2 + i % 2, cell_y * 3 + i / 2);
+        *colour = (frame.get(x as u32, y as u32) + error[x + y * width]).clamp();
+      }
+
+      let pair = cell_pair(&cell, palette);
+      for i in 0..6 {
+        let (x, y) = (cell_x * 2 + i % 2, cell_y * 3 + i / 2);
+        let colour = (frame.get(x as u32, y as u32) + error[x + y * width]).clamp();
+        let chosen = nearest_of(colour, pair, palette);
+        buffer.set(x as u32, y as u32, chosen);
+        done[x + y * width] = true;
+
+        let remaining = colour - palette.get(chosen);
+        let mut below = 5.0 / 16.0;
+        if x + 1 < width {
+          error[x + 1 + y * width] += remaining * (7.0 / 16.0);
+        }
+        if y + 1 < height {
+          if x > 0 && !done[x - 1 + (y + 1) * width] {
+            error[x - 1 + (y + 1) * width] += remaining * (3.0 / 16.0);
+          } else {
+            below += 3.0 / 16.0;
+          }
+          if x + 1 < width {
+            error[x + 1 + (y + 1) * width] += remaining * (1.0 / 16.0);
+          }
+          error[x + (y + 1) * width] += remaining * below;
+        }
+      }
+    }
+  }
+
+  buffer
+}
+
+/// Draw a colour which falls between two palette colours as a pattern of both,
+/// rather than snapping it to the nearest one. This is used by the renderer for
+/// smooth gradients (such as the sky), which would otherwise be drawn as hard
+/// bands when the session doesn't dither.
+///
+/// `x` and `y` are the pixel's position in the frame, and pick its threshold
+/// from [`BAYER`].
+pub fn ordered(colour: Rgb, palette: &Palette, x: u32, y: u32) -> Rgb {
+  let (nearest, next) = palette.nearest_pair(colour);
+  let (nearest, next) = (palette.get(nearest), palette.get(next));
+
+  // How far along the line between the two colours this one lies.
+  let (offset, span) = (colour - nearest, next - nearest);
+  let length = span.r * span.r + span.g * span.g + span.b * span.b;
+  let along = if length > 0.0 {
+    (offset.r * span.r + offset.g * span.g + offset.b * span.b) / length
+  } else {
+    0.0
+  };
+
+  let threshold = (BAYER[y as usize % 3][x as usize % 2] + 0.5) / 6.0;
+  if along > threshold {
+    next
+  } else {
+    nearest
+  }
+}
+
+/// Reduce a frame to the colours in the palette, using the given dithering
+/// mode.
+pub fn quantise(frame: &Frame, palette: &Palette, mode: Dither) -> Buffer {
+  match mode {
+    Dither::None => quantise_nearest(frame, palette),
+    Dither::Bayer => quantise_bayer(frame, palette),
+    Dither::FloydSteinberg => quantise_floyd_steinberg(frame, palette),
+  }
+}
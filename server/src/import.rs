@@ -0,0 +1,224 @@
+//! Loads worlds from files: our own JSON and binary formats, Sponge schematics
+//! (`.schem`), and vanilla structure files (`.nbt`).
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::warn;
+use quartz_nbt::io::{read_nbt, Flavor};
+use quartz_nbt::{NbtCompound, NbtList};
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use crate::world::{check_size, Block, Blocks, Grid, World};
+
+/// The palette of a schematic or structure file, mapping Minecraft block states
+/// (such as `minecraft:oak_log[axis=y]`) to our blocks.
+///
+/// The block's properties are ignored, and any blocks not in the registry are
+/// replaced with air, in the same way as `c33d scan`.
+struct BlockPalette<'a> {
+  entries: Vec<(&'a str, Option<Block>)>,
+  /// The unknown blocks which were actually placed.
+  unknown: BTreeSet<&'a str>,
+}
+
+impl<'a> BlockPalette<'a> {
+  fn new() -> BlockPalette<'a> {
+    BlockPalette { entries: Vec::new(), unknown: BTreeSet::new() }
+  }
+
+  fn set(&mut self, index: usize, state: &'a str, blocks: &Blocks) {
+    let id = state.split('[').next().unwrap_or(state);
+    if self.entries.len() <= index {
+      self
+        .entries
+        .resize(index + 1, ("minecraft:air", Some(Block::AIR)));
+    }
+    self.entries[index] = (id, blocks.find(id));
+  }
+
+  fn get(&mut self, index: usize) -> Result<Block> {
+    match self.entries.get(index) {
+      None => bail!("Unknown palette index {}", index),
+      Some((_, Some(block))) => Ok(*block),
+      Some((id, None)) => {
+        self.unknown.insert(id);
+        Ok(Block::AIR)
+      }
+    }
+  }
+
+  fn finish(self, path: &Path) {
+    if !self.unknown.is_empty() {
+      let unknown: Vec<&str> = self.unknown.into_iter().collect();
+      warn!("{}: Replaced unknown blocks with air: {}", path.display(), unknown.join(", "));
+    }
+  }
+}
+
+fn read_compound(path: &Path) -> Result<NbtCompound> {
+  let data = std::fs::read(path)?;
+
+  // Schematics and structures are normally gzipped, but may not be.
+  let flavor = if data.starts_with(&[0x1f, 0x8b]) {
+    Flavor::GzCompressed
+  } else {
+    Flavor::Uncompressed
+  };
+  let (root, _) = read_nbt(&mut data.as_slice(), flavor)?;
+  Ok(root)
+}
+
+fn dimension(size: i64) -> Result<usize> {
+  usize::try_from(size).map_err(|_| anyhow!("Invalid size {}", size))
+}
+
+/// Load a Sponge schematic (versions 2 and 3).
+fn load_schematic<'a>(
+  root: &'a NbtCompound,
+  blocks: &Blocks,
+  palette: &mut BlockPalette<'a>,
+  max_volume: usize,
+) -> Result<World> {
+  // Version 3 nests everything inside a "Schematic" tag, and moves the palette
+  // and block data into "Blocks".
+  let schematic = root.get::<_, &NbtCompound>("Schematic").unwrap_or(root);
+  let (states, data) = match schematic.get::<_, &NbtCompound>("Blocks") {
+    Ok(container) => {
+      (container.get::<_, &NbtCompound>("Palette")?, container.get::<_, &[u8]>("Data")?)
+    }
+    Err(_) => {
+      (schematic.get::<_, &NbtCompound>("Palette")?, schematic.get::<_, &[u8]>("BlockData")?)
+    }
+  };
+
+  // Sizes are stored as shorts, but should be treated as unsigned.
+  let size = |name: &str| -> Result<usize> { Ok(schematic.get::<_, i16>(name)? as u16 as usize) };
+  let (width, height, length) = (size("Width")?, size("Height")?, size("Length")?);
+  check_size(width, height, length, max_volume)?;
+
+  for (state, index) in states.iter_map::<i32>() {
+    let index = usize::try_from(index?).map_err(|_| anyhow!("Invalid palette index"))?;
+    palette.set(index, state, blocks);
+  }
+
+  // Block data is a list of palette indexes, each stored as a varint.
+  let mut data = data.iter();
+  let mut world = World::new(width, height, length);
+  for y in 0..height {
+    for z in 0..length {
+      for x in 0..width {
+        let mut index = 0usize;
+        for shift in (0..).step_by(7) {
+          let byte = *data
+            .next()
+            .ok_or_else(|| anyhow!("Block data is truncated"))?;
+          if shift > 28 {
+            bail!("Invalid palette index");
+          }
+          index |= ((byte & 0x7f) as usize) << shift;
+          if byte & 0x80 == 0 {
+            break;
+          }
+        }
+
+        world.set(x, y, z, palette.get(index)?);
+      }
+    }
+  }
+
+  world.compact();
+  Ok(world)
+}
+
+/// Load a vanilla structure file, as saved by structure blocks.
+fn load_structure<'a>(
+  root: &'a NbtCompound,
+  blocks: &Blocks,
+  palette: &mut BlockPalette<'a>,
+  max_volume: usize,
+) -> Result<World> {
+  let size: Vec<i32> = root
+    .get::<_, &NbtList>("size")?
+    .iter_map::<i32>()
+    .collect::<Result<_, _>>()?;
+  let (width, height, depth) = match size[..] {
+    [x, y, z] => (dimension(x.into())?, dimension(y.into())?, dimension(z.into())?),
+    _ => bail!("Invalid size"),
+  };
+  check_size(width, height, depth, max_volume)?;
+
+  // Structures with several variants (such as shipwrecks) have a list of
+  // palettes. We just use the first one.
+  let states = match root.get::<_, &NbtList>("palette") {
+    Ok(states) => states,
+    Err(_) => root.get::<_, &NbtList>("palettes")?.get::<&NbtList>(0)?,
+  };
+  for (index, state) in states.iter_map::<&NbtCompound>().enumerate() {
+    palette.set(index, state?.get::<_, &str>("Name")?, blocks);
+  }
+
+  // Any positions not in the block list are structure voids, which we treat as
+  // air.
+  let mut world = World::new(width, height, depth);
+  for block in root
+    .get::<_, &NbtList>("blocks")?
+    .iter_map::<&NbtCompound>()
+  {
+    let block = block?;
+    let pos: Vec<i32> = block
+      .get::<_, &NbtList>("pos")?
+      .iter_map::<i32>()
+      .collect::<Result<_, _>>()?;
+    let (x, y, z) = match pos[..] {
+      [x, y, z] => (dimension(x.into())?, dimension(y.into())?, dimension(z.into())?),
+      _ => bail!("Invalid block position"),
+    };
+    if x >= width || y >= height || z >= depth {
+      bail!("Block at {}, {}, {} is outside the structure", x, y, z);
+    }
+
+    let state = usize::try_from(block.get::<_, i32>("state")?)?;
+    world.set(x, y, z, palette.get(state)?);
+  }
+
+  world.compact();
+  Ok(world)
+}
+
+/// The file extensions of every format [`load`] understands.
+pub const EXTENSIONS: [&str; 4] = ["json", "c33d", "schem", "nbt"];
+
+/// Load a world from a file, choosing the format based on its extension:
+///
+///  - `.json`: A [`Grid`], as produced by `c33d scan`.
+///  - `.c33d`: Our compact binary format (see [`World::from_binary`]).
+///  - `.schem`: A Sponge schematic, as produced by WorldEdit.
+///  - `.nbt`: A vanilla structure file.
+pub fn load(path: &Path, blocks: &Blocks, max_volume: usize) -> Result<World> {
+  load_inner(path, blocks, max_volume).with_context(|| format!("Failed to load {}", path.display()))
+}
+
+fn load_inner(path: &Path, blocks: &Blocks, max_volume: usize) -> Result<World> {
+  let extension = path.extension().and_then(|x| x.to_str()).unwrap_or("");
+  let world = match extension {
+    "json" => {
+      let grid: Grid = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+      World::from_grid(&grid, blocks, max_volume)?
+    }
+    "c33d" => World::from_binary(&std::fs::read(path)?, blocks, max_volume)?,
+    "schem" | "nbt" => {
+      let root = read_compound(path)?;
+      let mut palette = BlockPalette::new();
+      let world = if extension == "schem" {
+        load_schematic(&root, blocks, &mut palette, max_volume)?
+      } else {
+        load_structure(&root, blocks, &mut palette, max_volume)?
+      };
+      palette.finish(path);
+      world
+    }
+    _ => bail!("Unknown world format {:?}", extension),
+  };
+
+  Ok(world)
+}
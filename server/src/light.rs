@@ -0,0 +1,142 @@
+//! Lighting for rendered scenes: a single directional light (the sun), softened
+//! by ambient occlusion in the corners between blocks, and optionally casting
+//! shadows.
+
+use crate::ray::{nudge, trace_until, Hit, Plane, Vec3};
+use crate::world::{Blocks, World};
+
+/// The direction of the sun used when a session doesn't specify one. This
+/// lights the tops of blocks most brightly, followed by their south and then
+/// west faces.
+pub const DEFAULT_SUN: Vec3<f64> = Vec3 { x: -0.3, y: 1.0, z: 0.5 };
+
+/// How brightly faces facing away from the sun (or in shadow) are lit.
+const AMBIENT: f32 = 0.6;
+
+/// How much a fully occluded corner is darkened.
+const OCCLUSION: f32 = 0.4;
+
+/// The brightness levels which textures are shown at. Palettes are built from
+/// each texture at every one of these levels, so that they contain lighter and
+/// darker versions of each colour.
+pub const SHADES: [f32; 4] = [1.0, 0.8, 0.6, 0.4];
+
+/// How a scene is lit.
+#[derive(Copy, Clone, Debug)]
+pub struct Lighting {
+  /// The direction towards the sun, normalised to a unit vector.
+  sun: Vec3<f64>,
+  /// Whether blocks cast shadows.
+  shadows: bool,
+}
+
+impl Lighting {
+  /// Light a scene with the sun in the given direction (pointing from the scene
+  /// towards the sun). Returns [`None`] if the direction has no length.
+  ///
+  /// When `shadows` is set, a second ray is traced from every point facing the
+  /// sun to check whether it is in shadow. This makes rendering slower.
+  pub fn new(sun: Vec3<f64>, shadows: bool) -> Option<Lighting> {
+    let length = (sun.x * sun.x + sun.y * sun.y + sun.z * sun.z).sqrt();
+    if length == 0.0 || !length.is_finite() {
+      return None;
+    }
+
+    let sun = Vec3::new(sun.x / length, sun.y / length, sun.z / length);
+    Some(Lighting { sun, shadows })
+  }
+
+  /// The direction towards the sun, as a unit vector.
+  pub fn sun(&self) -> Vec3<f64> {
+    self.sun
+  }
+
+  /// How brightly lit a ray trace collision is, between 0 and 1.
+  pub fn shade(&self, world: &World, blocks: &Blocks, hit: &Hit) -> f32 {
+    let normal = hit.normal;
+    let mut facing =
+      normal.x as f64 * self.sun.x + normal.y as f64 * self.sun.y + normal.z as f64 * self.sun.z;
+    if self.shadows && facing > 0.0 && self.in_shadow(world, blocks, hit) {
+      facing = 0.0;
+    }
+    let direct = AMBIENT + (1.0 - AMBIENT) * facing.max(0.0) as f32;
+
+    direct * (1.0 - OCCLUSION * (1.0 - ambient_occlusion(world, blocks, hit)))
+  }
+
+  /// Check whether the sun is blocked from a point, by tracing a ray from it
+  /// towards the sun.
+  fn in_shadow(&self, world: &World, blocks: &Blocks, hit: &Hit) -> bool {
+    // Start just in front of the face, so the ray doesn't hit the block itself.
+    // Light passes through translucent blocks, so they don't cast shadows.
+    let start = nudge(hit.point(), hit.normal, 1.0);
+    trace_until(world, start, self.sun, |block| blocks.get(block).is_opaque()).is_some()
+  }
+}
+
+impl Default for Lighting {
+  fn default() -> Lighting {
+    Lighting::new(DEFAULT_SUN, false).unwrap()
+  }
+}
+
+/// Whether the block at this position casts shade on its neighbours.
+fn occludes(world: &World, blocks: &Blocks, x: i64, y: i64, z: i64) -> bool {
+  (0..world.width as i64).contains(&x)
+    && (0..world.height as i64).contains(&y)
+    && (0..world.depth as i64).contains(&z)
+    && blocks
+      .get(world.get(x as usize, y as usize, z as usize))
+      .is_opaque()
+}
+
+/// Compute how much light reaches the point on a face which was hit, between 0
+/// (fully occluded) and 1.
+///
+/// This uses the standard technique for voxel worlds: the light at each corner
+/// of the face depends on the three blocks in front of the face which touch that
+/// corner, and the light across the rest of the face is interpolated between
+/// the corners.
+fn ambient_occlusion(world: &World, blocks: &Blocks, hit: &Hit) -> f32 {
+  // The two axes running along the face, and how far along each of them the
+  // hit was.
+  let (u_axis, v_axis, u, v) = match hit.side {
+    Plane::X => ((0, 0, 1), (0, 1, 0), hit.offset.0, 1.0 - hit.offset.1),
+    Plane::Y => ((1, 0, 0), (0, 0, 1), hit.offset.0, hit.offset.1),
+    Plane::Z => ((1, 0, 0), (0, 1, 0), hit.offset.0, 1.0 - hit.offset.1),
+  };
+  let (u, v) = (u.clamp(0.0, 1.0) as f32, v.clamp(0.0, 1.0) as f32);
+
+  // The block in front of the face, and so next to the ray.
+  let front = Vec3::new(
+    hit.position.x + hit.normal.x,
+    hit.position.y + hit.normal.y,
+    hit.position.z + hit.normal.z,
+  );
+  let occludes_at = |du: i64, dv: i64| {
+    occludes(
+      world,
+      blocks,
+      front.x + du * u_axis.0 + dv * v_axis.0,
+      front.y + du * u_axis.1 + dv * v_axis.1,
+      front.z + du * u_axis.2 + dv * v_axis.2,
+    )
+  };
+  // Each side touches two corners, so only look them up once.
+  let (left, right) = (occludes_at(-1, 0), occludes_at(1, 0));
+  let (bottom, top) = (occludes_at(0, -1), occludes_at(0, 1));
+  let corner = |side_u: bool, side_v: bool, du: i64, dv: i64| {
+    if side_u && side_v {
+      0.0
+    } else {
+      (3 - side_u as u8 - side_v as u8 - occludes_at(du, dv) as u8) as f32 / 3.0
+    }
+  };
+
+  let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+  lerp(
+    lerp(corner(left, bottom, -1, -1), corner(right, bottom, 1, -1), u),
+    lerp(corner(left, top, -1, 1), corner(right, top, 1, 1), u),
+    v,
+  )
+}
@@ -1,13 +1,30 @@
 mod buffer;
+mod cache;
+mod compression;
+mod dither;
+mod import;
+mod light;
+mod palette;
 mod ray;
 mod routes;
+mod sky;
 mod texture;
+mod watch;
 mod world;
 
-use texture::Textures;
+use buffer::{MAX_HEIGHT, MAX_WIDTH};
+use cache::WorldCache;
+use light::{Lighting, DEFAULT_SUN};
+use ray::{Facing, Options, Screen, Vec3};
+use texture::{SharedTextures, TexturePack, Textures};
+use world::Blocks;
 
-use clap::Parser;
+use anyhow::{bail, Result};
+use clap::{Parser, Subcommand};
+use log::{error, info};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Instant;
 use warp::Filter;
 
 #[derive(Parser, Debug)]
@@ -20,15 +37,166 @@ struct Args {
   /// The port this server is hosted on.
   #[clap(long, default_value_t = 8080)]
   port: u16,
+
+  /// A JSON file defining the available blocks. Defaults to the built-in
+  /// `blocks.json`.
+  #[clap(long, global = true)]
+  blocks: Option<std::path::PathBuf>,
+
+  /// A directory of textures to use instead of the built-in ones. This should
+  /// contain a `textures.json` manifest mapping texture names to files.
+  ///
+  /// Both this and `--blocks` are watched for changes, and reloaded while the
+  /// server is running.
+  #[clap(long, global = true)]
+  textures: Option<std::path::PathBuf>,
+
+  /// The maximum number of blocks a world may contain.
+  #[clap(long, default_value_t = 1 << 24, global = true)]
+  max_world_volume: usize,
+
+  /// A directory of worlds to load when the server starts. Each world can then
+  /// be shown using its file name (without the extension) as its id.
+  #[clap(long)]
+  worlds: Option<std::path::PathBuf>,
+
+  #[clap(subcommand)]
+  command: Option<Command>,
 }
 
+#[derive(Subcommand, Debug)]
+enum Command {
+  /// Convert a world to JSON (`.json`) or our binary format (`.c33d`), based on
+  /// the output file's extension. The input may be in either of these formats,
+  /// a Sponge schematic (`.schem`) or a vanilla structure file (`.nbt`).
+  Convert { input: PathBuf, output: PathBuf },
+
+  /// Render a world from a range of positions, and report how long each frame
+  /// took on average. The monitor is placed in the middle of the world.
+  Bench {
+    world: PathBuf,
+
+    /// The number of frames to render.
+    #[clap(long, default_value_t = 200)]
+    frames: u32,
+
+    /// Whether blocks should cast shadows.
+    #[clap(long)]
+    shadows: bool,
+  },
+}
+
+/// The largest world which may be uploaded with `PUT /worlds/{id}`, in bytes.
+const MAX_UPLOAD_SIZE: u64 = 64 * 1024 * 1024;
+
 fn with_context<T: Sync + Send>(
-  obj: T,
+  obj: Arc<T>,
 ) -> impl Filter<Extract = (Arc<T>,), Error = std::convert::Infallible> + Clone {
-  let obj = Arc::new(obj);
   warp::any().map(move || obj.clone())
 }
 
+/// Load the block definitions given on the command line.
+fn load_blocks(blocks: Option<&Path>) -> Result<Blocks> {
+  match blocks {
+    None => Blocks::load(include_str!("../../blocks.json")),
+    Some(path) => Blocks::load(&std::fs::read_to_string(path)?),
+  }
+}
+
+/// Load the block definitions and textures given on the command line.
+fn load_textures(blocks: Option<&Path>, textures: Option<&Path>) -> Result<Textures> {
+  let blocks = load_blocks(blocks)?;
+  let pack = textures.map(TexturePack::open).transpose()?;
+  Textures::new(blocks, pack.as_ref())
+}
+
+/// Convert a world between formats (see [`Command::Convert`]).
+fn convert(args: &Args, input: &Path, output: &Path) -> Result<()> {
+  let blocks = load_blocks(args.blocks.as_deref())?;
+  let world = import::load(input, &blocks, args.max_world_volume)?;
+
+  let data = match output.extension().and_then(|x| x.to_str()) {
+    Some("json") => serde_json::to_vec(&world.to_grid(&blocks))?,
+    Some("c33d") => world.to_binary(&blocks)?,
+    _ => bail!("Unknown output format for {}", output.display()),
+  };
+  std::fs::write(output, data)?;
+  Ok(())
+}
+
+/// Benchmark the renderer (see [`Command::Bench`]).
+fn bench(args: &Args, world: &Path, frames: u32, shadows: bool) -> Result<()> {
+  let textures = load_textures(args.blocks.as_deref(), args.textures.as_deref())?;
+  let world = import::load(world, textures.blocks(), args.max_world_volume)?;
+  info!(
+    "Loaded world ({}x{}x{}, {} bytes)",
+    world.width,
+    world.height,
+    world.depth,
+    world.memory_usage()
+  );
+
+  // The largest screen a client may ask for: an 8x6 monitor at the smallest
+  // text scale, less its border.
+  let facings = [Facing::North, Facing::East, Facing::South, Facing::West];
+  let mut screen = Screen {
+    facing: Facing::North,
+    width: MAX_WIDTH,
+    height: MAX_HEIGHT,
+    physical_width: 8.0,
+    physical_height: 6.0,
+  };
+  let options =
+    Options { lighting: Lighting::new(DEFAULT_SUN, shadows).unwrap(), ..Options::default() };
+  let offset =
+    Vec3::new(world.width as f64 / 2.0, world.height as f64 / 2.0, world.depth as f64 / 2.0);
+
+  let start = Instant::now();
+  for frame in 0..frames {
+    // Walk the player from one side of the monitor to the other, turning to
+    // face the next direction every few frames.
+    let progress = (frame % 50) as f64 / 50.0;
+    screen.facing = facings[(frame / 50) as usize % facings.len()];
+    let position = screen.facing.rotate(Vec3::new(progress * 8.0, 1.5, 2.0));
+    ray::render(&world, &textures, &options, &screen, offset, position);
+  }
+  let elapsed = start.elapsed();
+
+  info!(
+    "Rendered {} frames in {:.2?} ({:.2?} per frame)",
+    frames,
+    elapsed,
+    elapsed / frames.max(1)
+  );
+  Ok(())
+}
+
+/// Load every world in a directory into the cache, using their file names as
+/// their ids. Files which aren't worlds (judging by their extension) are
+/// skipped.
+fn preload(cache: &WorldCache, textures: &SharedTextures, dir: &Path) -> Result<()> {
+  for entry in std::fs::read_dir(dir)? {
+    let path = entry?.path();
+    let extension = path.extension().and_then(|x| x.to_str()).unwrap_or("");
+    let id = match path.file_stem().and_then(|x| x.to_str()) {
+      Some(id) if path.is_file() && import::EXTENSIONS.contains(&extension) => id.to_string(),
+      _ => {
+        info!("Skipping {}, which isn't a world", path.display());
+        continue;
+      }
+    };
+    if cache::is_content_id(&id) {
+      bail!("{} uses an id reserved for worlds sent by clients", path.display());
+    }
+
+    let world = import::load(&path, textures.get().blocks(), cache.max_volume())?;
+    info!("Loaded world {:?} ({}x{}x{})", id, world.width, world.height, world.depth);
+    cache.put(id, world);
+  }
+
+  Ok(())
+}
+
 #[tokio::main]
 async fn main() {
   {
@@ -44,16 +212,60 @@ async fn main() {
 
   let args = Args::parse();
 
-  let textures = with_context(Textures::new().unwrap());
+  if let Some(command) = &args.command {
+    let result = match command {
+      Command::Convert { input, output } => convert(&args, input, output),
+      Command::Bench { world, frames, shadows } => bench(&args, world, *frames, *shadows),
+    };
+    if let Err(err) = result {
+      error!("{:?}", err);
+      std::process::exit(1);
+    }
+    return;
+  }
+
+  let shared_textures = Arc::new(SharedTextures::new(
+    load_textures(args.blocks.as_deref(), args.textures.as_deref()).unwrap(),
+  ));
+
+  if args.blocks.is_some() || args.textures.is_some() {
+    let (blocks, textures) = (args.blocks.clone(), args.textures.clone());
+    let paths = blocks.iter().chain(textures.iter()).cloned().collect();
+    watch::watch(paths, shared_textures.clone(), move || {
+      load_textures(blocks.as_deref(), textures.as_deref())
+    });
+  }
+
+  let cache = Arc::new(WorldCache::new(args.max_world_volume));
+  if let Some(worlds) = &args.worlds {
+    preload(&cache, &shared_textures, worlds).unwrap();
+  }
+
+  let textures = with_context(shared_textures);
+  let cache = with_context(cache);
 
   let metrics = warp::path("metrics").map(routes::metrics);
 
   let render = warp::path("render")
     .and(warp::ws())
     .and(textures.clone())
+    .and(cache.clone())
     .map(routes::render);
 
-  warp::serve(metrics.or(render))
+  let put_world = warp::put()
+    .and(warp::path!("worlds" / String))
+    .and(warp::body::content_length_limit(MAX_UPLOAD_SIZE))
+    .and(warp::body::json())
+    .and(textures.clone())
+    .and(cache.clone())
+    .map(routes::put_world);
+
+  let blocks = warp::get()
+    .and(warp::path!("blocks"))
+    .and(textures.clone())
+    .map(routes::blocks);
+
+  warp::serve(metrics.or(render).or(put_world).or(blocks))
     .run((args.host, args.port))
     .await;
 }
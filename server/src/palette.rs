@@ -0,0 +1,261 @@
+//! Colours, and the 16-colour palette they are drawn with.
+
+use std::collections::HashMap;
+
+use crate::buffer::Colour;
+
+/// The number of colours available on a ComputerCraft terminal.
+pub const PALETTE_SIZE: usize = 16;
+
+/// A colour, with each channel between 0 and 255.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Rgb {
+  pub r: f32,
+  pub g: f32,
+  pub b: f32,
+}
+
+impl Rgb {
+  pub const fn new(r: f32, g: f32, b: f32) -> Rgb {
+    Rgb { r, g, b }
+  }
+
+  /// Convert a colour in the form `0xRRGGBB`.
+  pub const fn from_hex(hex: u32) -> Rgb {
+    Rgb::new(((hex >> 16) & 0xff) as f32, ((hex >> 8) & 0xff) as f32, (hex & 0xff) as f32)
+  }
+
+  /// Convert this colour to the form `0xRRGGBB`, as used by CC's
+  /// `setPaletteColour`.
+  pub fn to_hex(self) -> u32 {
+    let channel = |x: f32| x.round().clamp(0.0, 255.0) as u32;
+    (channel(self.r) << 16) | (channel(self.g) << 8) | channel(self.b)
+  }
+
+  /// Clamp each channel to between 0 and 255.
+  pub fn clamp(self) -> Rgb {
+    Rgb::new(self.r.clamp(0.0, 255.0), self.g.clamp(0.0, 255.0), self.b.clamp(0.0, 255.0))
+  }
+
+  fn channel(self, channel: usize) -> f32 {
+    match channel {
+      0 => self.r,
+      1 => self.g,
+      _ => self.b,
+    }
+  }
+
+  /// The perceptual distance between two colours. This uses the "redmean"
+  /// approximation, which weights each channel differently depending on how
+  /// red the colours are.
+  pub fn distance(self, other: Rgb) -> f32 {
+    let mean_r = (self.r + other.r) / 2.0;
+    let (r, g, b) = (self.r - other.r, self.g - other.g, self.b - other.b);
+    (2.0 + mean_r / 256.0) * r * r + 4.0 * g * g + (2.0 + (255.0 - mean_r) / 256.0) * b * b
+  }
+}
+
+impl std::ops::Add for Rgb {
+  type Output = Rgb;
+
+  fn add(self, other: Rgb) -> Rgb {
+    Rgb::new(self.r + other.r, self.g + other.g, self.b + other.b)
+  }
+}
+
+impl std::ops::AddAssign for Rgb {
+  fn add_assign(&mut self, other: Rgb) {
+    *self = *self + other;
+  }
+}
+
+impl std::ops::Sub for Rgb {
+  type Output = Rgb;
+
+  fn sub(self, other: Rgb) -> Rgb {
+    Rgb::new(self.r - other.r, self.g - other.g, self.b - other.b)
+  }
+}
+
+impl std::ops::Mul<f32> for Rgb {
+  type Output = Rgb;
+
+  fn mul(self, scale: f32) -> Rgb {
+    Rgb::new(self.r * scale, self.g * scale, self.b * scale)
+  }
+}
+
+/// A box of colours (and how often each one is used), as used by the median-cut
+/// algorithm in [`Palette::quantise`].
+struct ColourBox {
+  colours: Vec<(Rgb, usize)>,
+}
+
+impl ColourBox {
+  /// Find the channel with the largest range of values, returning the channel
+  /// and that range.
+  fn widest_channel(&self) -> (usize, f32) {
+    (0..3)
+      .map(|channel| {
+        let values = self.colours.iter().map(|(c, _)| c.channel(channel));
+        let min = values.clone().fold(f32::MAX, f32::min);
+        let max = values.fold(f32::MIN, f32::max);
+        (channel, max - min)
+      })
+      .max_by(|a, b| a.1.total_cmp(&b.1))
+      .unwrap()
+  }
+
+  /// Split this box in two at the median of its widest channel.
+  fn split(mut self) -> (ColourBox, ColourBox) {
+    let (channel, _) = self.widest_channel();
+    self
+      .colours
+      .sort_by(|(a, _), (b, _)| a.channel(channel).total_cmp(&b.channel(channel)));
+
+    let total: usize = self.colours.iter().map(|(_, count)| count).sum();
+    let mut seen = 0;
+    let mut median = self.colours.len() - 1;
+    for (i, (_, count)) in self.colours.iter().enumerate() {
+      seen += count;
+      if seen * 2 >= total {
+        median = (i + 1).clamp(1, self.colours.len() - 1);
+        break;
+      }
+    }
+
+    let upper = self.colours.split_off(median);
+    (self, ColourBox { colours: upper })
+  }
+
+  /// The average colour of this box, weighted by how often each colour is used.
+  fn average(&self) -> Rgb {
+    let (mut r, mut g, mut b, mut total) = (0.0, 0.0, 0.0, 0.0);
+    for (colour, count) in &self.colours {
+      let count = *count as f32;
+      r += colour.r * count;
+      g += colour.g * count;
+      b += colour.b * count;
+      total += count;
+    }
+
+    Rgb::new(r / total, g / total, b / total)
+  }
+}
+
+/// The colours a terminal is drawn with. This is derived from the colours
+/// actually used by our textures (see [`Palette::quantise`]), and sent to the
+/// client when it connects.
+pub struct Palette {
+  colours: [Rgb; PALETTE_SIZE],
+  /// The distance between each pair of colours in the palette.
+  distances: [[f32; PALETTE_SIZE]; PALETTE_SIZE],
+}
+
+impl Palette {
+  /// Pick a palette which best represents the given colours, using the
+  /// median-cut algorithm. When there are fewer unique colours than available
+  /// slots, each colour is represented exactly.
+  ///
+  /// The last colour in the palette is always black, which is used for the
+  /// monitor's border.
+  pub fn quantise(colours: impl IntoIterator<Item = Rgb>) -> Palette {
+    let mut counts: HashMap<u32, usize> = HashMap::new();
+    for colour in colours {
+      *counts.entry(colour.to_hex()).or_insert(0) += 1;
+    }
+
+    let mut counts: Vec<(u32, usize)> = counts.into_iter().collect();
+    counts.sort_unstable();
+
+    let mut boxes = Vec::new();
+    if !counts.is_empty() {
+      boxes.push(ColourBox {
+        colours: counts
+          .into_iter()
+          .map(|(c, n)| (Rgb::from_hex(c), n))
+          .collect(),
+      });
+    }
+
+    while boxes.len() < PALETTE_SIZE - 1 {
+      // Split the box with the widest range of colours. If every box contains a
+      // single colour, then we're done.
+      let widest = boxes
+        .iter()
+        .enumerate()
+        .filter(|(_, b)| b.colours.len() > 1)
+        .max_by(|(_, a), (_, b)| a.widest_channel().1.total_cmp(&b.widest_channel().1));
+      let index = match widest {
+        None => break,
+        Some((index, _)) => index,
+      };
+
+      let (lower, upper) = boxes.swap_remove(index).split();
+      boxes.push(lower);
+      boxes.push(upper);
+    }
+
+    let mut palette = [Rgb::new(0.0, 0.0, 0.0); PALETTE_SIZE];
+    for (slot, colour_box) in palette.iter_mut().zip(&boxes) {
+      *slot = colour_box.average();
+    }
+
+    Palette::new(palette)
+  }
+
+  fn new(colours: [Rgb; PALETTE_SIZE]) -> Palette {
+    let mut distances = [[0.0; PALETTE_SIZE]; PALETTE_SIZE];
+    for (a, row) in distances.iter_mut().enumerate() {
+      for (b, distance) in row.iter_mut().enumerate() {
+        *distance = colours[a].distance(colours[b]);
+      }
+    }
+
+    Palette { colours, distances }
+  }
+
+  /// The perceptual distance between two colours in this palette.
+  pub fn distance(&self, a: Colour, b: Colour) -> f32 {
+    self.distances[a as usize][b as usize]
+  }
+
+  /// Find the palette colour closest to this one.
+  pub fn nearest(&self, colour: Rgb) -> Colour {
+    let mut best = (0, f32::MAX);
+    for (i, candidate) in self.colours.iter().enumerate() {
+      let distance = colour.distance(*candidate);
+      if distance < best.1 {
+        best = (i, distance);
+      }
+    }
+
+    best.0 as Colour
+  }
+
+  /// Find the two palette colours closest to this one, nearest first.
+  pub fn nearest_pair(&self, colour: Rgb) -> (Colour, Colour) {
+    let mut best = [(0, f32::MAX), (0, f32::MAX)];
+    for (i, candidate) in self.colours.iter().enumerate() {
+      let distance = colour.distance(*candidate);
+      if distance < best[0].1 {
+        best = [(i, distance), best[0]];
+      } else if distance < best[1].1 {
+        best[1] = (i, distance);
+      }
+    }
+
+    (best[0].0 as Colour, best[1].0 as Colour)
+  }
+
+  /// Get the RGB value of a colour in this palette.
+  pub fn get(&self, colour: Colour) -> Rgb {
+    self.colours[colour as usize]
+  }
+
+  /// Convert this palette to a list of `0xRRGGBB` colours, suitable for sending
+  /// to the client.
+  pub fn to_hex(&self) -> Vec<u32> {
+    self.colours.iter().map(|c| c.to_hex()).collect()
+  }
+}
@@ -1,19 +1,25 @@
 //! Traces rays through a [`World`] and renders them.
 
-use crate::buffer::{Buffer, BUF_HEIGHT, BUF_WIDTH};
-use crate::texture::{Textures, DEFAULT_COLOUR};
-use crate::world::{Block, World};
+use crate::buffer::Frame;
+use crate::dither::ordered;
+use crate::light::Lighting;
+use crate::palette::Rgb;
+use crate::sky::Sky;
+use crate::texture::Textures;
+use crate::world::{Block, Blocks, World};
 
 use log::warn;
 use rayon::prelude::*;
+use serde::Deserialize;
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug)]
 pub enum Plane {
   X,
   Y,
   Z,
 }
 
+#[derive(Copy, Clone, Debug, Deserialize)]
 pub struct Vec3<T> {
   pub x: T,
   pub y: T,
@@ -26,32 +32,96 @@ impl<T> Vec3<T> {
   }
 }
 
+/// The direction a monitor is facing, and so the opposite of the direction the
+/// player is looking in.
+#[derive(Copy, Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Facing {
+  #[default]
+  North,
+  South,
+  East,
+  West,
+}
+
+impl Facing {
+  /// Rotate a point on a north-facing screen to a screen facing this direction.
+  /// Points are relative to the monitor's block, and so are rotated around the
+  /// centre of that block (rather than its corner).
+  pub fn rotate(self, point: Vec3<f64>) -> Vec3<f64> {
+    let (x, z) = (point.x - 0.5, point.z - 0.5);
+    let (x, z) = match self {
+      Facing::North => (x, z),
+      Facing::South => (-x, -z),
+      Facing::East => (-z, x),
+      Facing::West => (z, -x),
+    };
+    Vec3::new(x + 0.5, point.y, z + 0.5)
+  }
+}
+
+/// The monitor we are rendering to.
+#[derive(Copy, Clone)]
+pub struct Screen {
+  pub facing: Facing,
+  /// The width of the monitor's terminal, in characters.
+  pub width: u32,
+  /// The height of the monitor's terminal, in characters.
+  pub height: u32,
+  /// The width of the monitor, in blocks.
+  pub physical_width: f64,
+  /// The height of the monitor, in blocks.
+  pub physical_height: f64,
+}
+
 pub struct Hit {
   pub block: Block,
   pub side: Plane,
   pub offset: (f64, f64),
+  /// The position of the block which was hit.
+  pub position: Vec3<i64>,
+  /// The direction the face which was hit is facing, as a unit vector along the
+  /// `side` axis.
+  pub normal: Vec3<i64>,
+  /// How far along the ray the hit was, as a multiple of the ray's direction.
+  pub t: f64,
+}
+
+impl Hit {
+  /// The point on the block's face where the ray hit it.
+  pub fn point(&self) -> Vec3<f64> {
+    let (u, v) = self.offset;
+    // Faces pointing in the positive direction are on the far side of the block.
+    let face = |position: i64, normal: i64| (position + normal.max(0)) as f64;
+    let Vec3 { x, y, z } = self.position;
+    match self.side {
+      Plane::X => Vec3::new(face(x, self.normal.x), (y + 1) as f64 - v, z as f64 + u),
+      Plane::Y => Vec3::new(x as f64 + u, face(y, self.normal.y), z as f64 + v),
+      Plane::Z => Vec3::new(x as f64 + u, (y + 1) as f64 - v, face(z, self.normal.z)),
+    }
+  }
 }
 
 fn get_dists(start: f64, direction: f64) -> (i64, i64, f64, f64) {
   let step = match direction {
-    i if i == 0.0 => 0,
+    0.0 => 0,
     i if i < 0.0 => -1,
     _ => 1,
   };
 
-  let delta_dist = if direction == 0.0 {
-    1e30
-  } else {
-    1.0 / direction.abs()
-  };
-  let map = start.trunc() as i64;
+  // Rays may start at negative coordinates (such as in front of a rotated
+  // screen), so round down rather than towards zero.
+  let map = start.floor() as i64;
+  let fract = start - start.floor();
 
-  let side_dist = delta_dist
-    * (if direction > 0.0 {
-      1.0 - start.fract()
-    } else {
-      start.fract()
-    });
+  // A ray which doesn't move along this axis never crosses it, even if it
+  // starts on a boundary.
+  if direction == 0.0 {
+    return (map, step, f64::INFINITY, f64::INFINITY);
+  }
+
+  let delta_dist = 1.0 / direction.abs();
+  let side_dist = delta_dist * (if direction > 0.0 { 1.0 - fract } else { fract });
 
   (map, step, delta_dist, side_dist)
 }
@@ -64,8 +134,77 @@ fn outside(value: i64, min: i64, max: i64, step: i64) -> bool {
   }
 }
 
-/** Trace a ray through the world. */
-pub fn trace(world: &World, start: Vec3<f64>, direction: Vec3<f64>) -> Option<Hit> {
+/// The number of steps along one axis until a ray leaves the cube it is
+/// currently in.
+fn steps_to_edge(map: i64, step: i64, size: i64) -> i64 {
+  let offset = map % size;
+  if step < 0 {
+    offset + 1
+  } else {
+    size - offset
+  }
+}
+
+/// The distance along the ray at which it crosses the last boundary on this
+/// axis before leaving the current cube.
+///
+/// This sums the distances in the same way as [`trace`], so that the result is
+/// exactly the same as stepping one block at a time.
+fn cube_exit(map: i64, step: i64, side_dist: f64, delta_dist: f64, size: i64) -> f64 {
+  if step == 0 {
+    return f64::INFINITY;
+  }
+
+  let mut side_dist = side_dist;
+  for _ in 1..steps_to_edge(map, step, size) {
+    side_dist += delta_dist;
+  }
+  side_dist
+}
+
+/// Step along one axis, crossing every boundary before `t` (and those at `t`,
+/// if `inclusive`) without leaving the current cube.
+fn skip_to(
+  (map, side_dist): (&mut i64, &mut f64),
+  step: i64,
+  delta_dist: f64,
+  size: i64,
+  t: f64,
+  inclusive: bool,
+) {
+  if step == 0 {
+    return;
+  }
+
+  for _ in 1..steps_to_edge(*map, step, size) {
+    if *side_dist < t || (inclusive && *side_dist == t) {
+      *map += step;
+      *side_dist += delta_dist;
+    } else {
+      break;
+    }
+  }
+}
+
+/** Trace a ray through the world, stopping at the first block which isn't
+ * transparent. */
+pub fn trace(
+  world: &World,
+  blocks: &Blocks,
+  start: Vec3<f64>,
+  direction: Vec3<f64>,
+) -> Option<Hit> {
+  trace_until(world, start, direction, |block| !blocks.get(block).transparent)
+}
+
+/** Trace a ray through the world, stopping at the first block for which `stops`
+ * returns true. The block containing `start` is never checked. */
+pub fn trace_until(
+  world: &World,
+  start: Vec3<f64>,
+  direction: Vec3<f64>,
+  stops: impl Fn(Block) -> bool,
+) -> Option<Hit> {
   let width = world.width as i64;
   let height = world.height as i64;
   let depth = world.depth as i64;
@@ -97,71 +236,97 @@ pub fn trace(world: &World, start: Vec3<f64>, direction: Vec3<f64>) -> Option<Hi
     }
 
     if (0..width).contains(&map_x) && (0..height).contains(&map_y) && (0..depth).contains(&map_z) {
-      match world.get(map_x as usize, map_y as usize, map_z as usize) {
-        Block::Air => (),
-        // TODO: Compute offset. How??
-        block => {
-          // Without loss of generality, pick our side to be x and face be closest to us. We have map_x == start.x +
-          // direction.x * t for some t. Solving for t gives (map_x - start.x) / direction.x
-          let (t, offset) = match side {
-            Plane::Z => {
-              let map_z = if step_z < 0 { map_z + 1 } else { map_z };
-              let t = (map_z as f64 - start.z) / direction.z;
-              (
-                t,
-                (
-                  start.x + direction.x * t - map_x as f64,
-                  1.0 - (start.y + direction.y * t - map_y as f64),
-                ),
-              )
-            }
-            Plane::X => {
-              let map_x = if step_x < 0 { map_x + 1 } else { map_x };
-              let t = (map_x as f64 - start.x) / direction.x;
+      let (x, y, z) = (map_x as usize, map_y as usize, map_z as usize);
+
+      // If this block is part of a larger empty cube, skip to the last block
+      // the ray passes through before leaving it. Each axis takes the same
+      // steps it would have taken anyway (breaking ties in favour of z, then y,
+      // as above), so the ray continues along exactly the same path.
+      let (block, size) = world.get_cube(x, y, z);
+      if size > 1 && !stops(block) {
+        let size = size as i64;
+        let exit_x = cube_exit(map_x, step_x, side_dist_x, delta_dist_x, size);
+        let exit_y = cube_exit(map_y, step_y, side_dist_y, delta_dist_y, size);
+        let exit_z = cube_exit(map_z, step_z, side_dist_z, delta_dist_z, size);
+        let (t, exit) = if exit_z <= exit_x && exit_z <= exit_y {
+          (exit_z, Plane::Z)
+        } else if exit_y <= exit_x {
+          (exit_y, Plane::Y)
+        } else {
+          (exit_x, Plane::X)
+        };
+
+        let (y_first, z_first) = (matches!(exit, Plane::X), !matches!(exit, Plane::Z));
+        skip_to((&mut map_x, &mut side_dist_x), step_x, delta_dist_x, size, t, false);
+        skip_to((&mut map_y, &mut side_dist_y), step_y, delta_dist_y, size, t, y_first);
+        skip_to((&mut map_z, &mut side_dist_z), step_z, delta_dist_z, size, t, z_first);
+        continue;
+      }
+
+      if stops(block) {
+        // Without loss of generality, pick our side to be x and face be closest to us. We have map_x == start.x +
+        // direction.x * t for some t. Solving for t gives (map_x - start.x) / direction.x
+        let (t, offset) = match side {
+          Plane::Z => {
+            let map_z = if step_z < 0 { map_z + 1 } else { map_z };
+            let t = (map_z as f64 - start.z) / direction.z;
+            (
+              t,
               (
-                t,
-                (
-                  start.z + direction.z * t - map_z as f64,
-                  1.0 - (start.y + direction.y * t - map_y as f64),
-                ),
-              )
-            }
-            Plane::Y => {
-              let map_y = if step_y < 0 { map_y + 1 } else { map_y };
-              let t = (map_y as f64 - start.y) / direction.y;
+                start.x + direction.x * t - map_x as f64,
+                1.0 - (start.y + direction.y * t - map_y as f64),
+              ),
+            )
+          }
+          Plane::X => {
+            let map_x = if step_x < 0 { map_x + 1 } else { map_x };
+            let t = (map_x as f64 - start.x) / direction.x;
+            (
+              t,
               (
-                t,
-                (
-                  start.x + direction.x * t - map_x as f64,
-                  start.z + direction.z * t - map_z as f64,
-                ),
-              )
-            }
-          };
-
-          if offset.0 > 1.0 || offset.1 > 1.0 || offset.0 < 0.0 || offset.1 < 0.0 {
-            warn!(
-            "Tracing ray from {},{},{} with {},{},{}. Collides at {},{},{} (t={}, side={:?}) => {}, {}, {} {:?}",
-            start.x,
-            start.y,
-            start.z,
-            direction.x,
-            direction.y,
-            direction.z,
-            map_x,
-            map_y,
-            map_z,
-            t,
-            side,
-            start.x + direction.x * t,
-            start.y + direction.y * t,
-            start.z + direction.z * t,
-            offset
-          );
+                start.z + direction.z * t - map_z as f64,
+                1.0 - (start.y + direction.y * t - map_y as f64),
+              ),
+            )
+          }
+          Plane::Y => {
+            let map_y = if step_y < 0 { map_y + 1 } else { map_y };
+            let t = (map_y as f64 - start.y) / direction.y;
+            (
+              t,
+              (start.x + direction.x * t - map_x as f64, start.z + direction.z * t - map_z as f64),
+            )
           }
+        };
 
-          return Some(Hit { block, side, offset });
+        if offset.0 > 1.0 || offset.1 > 1.0 || offset.0 < 0.0 || offset.1 < 0.0 {
+          warn!(
+          "Tracing ray from {},{},{} with {},{},{}. Collides at {},{},{} (t={}, side={:?}) => {}, {}, {} {:?}",
+          start.x,
+          start.y,
+          start.z,
+          direction.x,
+          direction.y,
+          direction.z,
+          map_x,
+          map_y,
+          map_z,
+          t,
+          side,
+          start.x + direction.x * t,
+          start.y + direction.y * t,
+          start.z + direction.z * t,
+          offset
+        );
         }
+
+        let normal = match side {
+          Plane::X => Vec3::new(-step_x, 0, 0),
+          Plane::Y => Vec3::new(0, -step_y, 0),
+          Plane::Z => Vec3::new(0, 0, -step_z),
+        };
+        let position = Vec3::new(map_x, map_y, map_z);
+        return Some(Hit { block, side, offset, position, normal, t });
       }
     } else if outside(map_x, 0, width, step_x)
       || outside(map_y, 0, height, step_y)
@@ -172,31 +337,301 @@ pub fn trace(world: &World, start: Vec3<f64>, direction: Vec3<f64>) -> Option<Hi
   }
 }
 
+/// How a scene should be rendered. This may be configured by each session.
+#[derive(Copy, Clone, Debug)]
+pub struct Options {
+  pub lighting: Lighting,
+  pub sky: Sky,
+  /// How far a ray may pass through a translucent block (such as water) before
+  /// nothing behind it can be seen, in blocks.
+  pub translucent_depth: f64,
+  pub fog: Option<Fog>,
+}
+
+impl Default for Options {
+  fn default() -> Options {
+    Options {
+      lighting: Lighting::default(),
+      sky: Sky::default(),
+      translucent_depth: DEFAULT_TRANSLUCENT_DEPTH,
+      fog: None,
+    }
+  }
+}
+
+/// Distance fog, which fades blocks into the sky the further away they are.
+#[derive(Copy, Clone, Debug)]
+pub struct Fog {
+  /// How far from the player blocks start to fade, in blocks.
+  pub start: f64,
+  /// How far from the player blocks are hidden completely, in blocks.
+  pub end: f64,
+}
+
+impl Fog {
+  /// How much of a block this far away is hidden, between 0 and 1.
+  fn amount(&self, distance: f64) -> f32 {
+    ((distance - self.start) / (self.end - self.start)).clamp(0.0, 1.0) as f32
+  }
+}
+
+/// The default value of [`Options::translucent_depth`].
+pub const DEFAULT_TRANSLUCENT_DEPTH: f64 = 4.0;
+
+/// How much of the colour behind a translucent block is hidden by its surface,
+/// however shallow it is.
+const SURFACE_OPACITY: f32 = 0.3;
+
+/// Move a point slightly along a face's normal, to get a point just in front of
+/// (or with a `distance` of -1, just behind) that face.
+pub fn nudge(point: Vec3<f64>, normal: Vec3<i64>, distance: f64) -> Vec3<f64> {
+  const BIAS: f64 = 1e-6;
+  Vec3::new(
+    point.x + normal.x as f64 * distance * BIAS,
+    point.y + normal.y as f64 * distance * BIAS,
+    point.z + normal.z as f64 * distance * BIAS,
+  )
+}
+
+/// Find the colour seen along a ray.
+///
+/// Translucent blocks tint whatever is behind them: we follow the ray through
+/// them, and blend their colour with whatever is behind them. The deeper the
+/// ray goes into them, the more their colour takes over.
+///
+/// Also returns whether this colour is part of a smooth gradient (the sky, or
+/// blocks fading into fog), and so should be dithered.
+fn colour_ray(
+  world: &World,
+  textures: &Textures,
+  options: &Options,
+  start: Vec3<f64>,
+  direction: Vec3<f64>,
+) -> (Rgb, bool) {
+  let blocks = textures.blocks();
+  let sky = options.sky.colour(direction);
+  let mut colour = Rgb::new(0.0, 0.0, 0.0);
+  // How much of the light from behind the blocks we've passed through so far
+  // is still visible.
+  let mut visible = 1.0;
+  let mut fogged = false;
+
+  // The length of the ray's direction, and so how far each step of `t` is. Rays
+  // start on the monitor, which is one step away from the player.
+  let length =
+    (direction.x * direction.x + direction.y * direction.y + direction.z * direction.z).sqrt();
+  // How far from the player the current trace started.
+  let mut origin = length;
+
+  let mut hit = trace(world, blocks, start, direction);
+  while visible > 0.01 {
+    let current = match hit {
+      None => return (colour + sky * visible, true),
+      Some(hit) => hit,
+    };
+
+    // Blocks past the end of the fog are hidden, as is everything behind them.
+    let distance = origin + current.t * length;
+    let fog = options.fog.map_or(0.0, |fog| fog.amount(distance));
+    if fog >= 1.0 {
+      return (colour + sky * visible, true);
+    }
+    fogged |= fog > 0.0;
+
+    let shaded = textures.get_colour(&current) * options.lighting.shade(world, blocks, &current);
+    let surface = shaded + (sky - shaded) * fog;
+    if !blocks.get(current.block).translucent {
+      return (colour + surface * visible, fogged);
+    }
+
+    // Follow the ray through this block, and any identical blocks behind it.
+    origin = distance;
+    let exit =
+      trace_until(world, nudge(current.point(), current.normal, -1.0), direction, |block| {
+        block != current.block
+      });
+    let depth = exit.as_ref().map_or(f64::INFINITY, |exit| exit.t * length);
+
+    let opacity = SURFACE_OPACITY
+      + (1.0 - SURFACE_OPACITY) * (depth / options.translucent_depth).min(1.0) as f32;
+    colour += surface * (visible * opacity);
+    visible *= 1.0 - opacity;
+
+    // If the ray came out into empty space, carry on tracing from there.
+    // Otherwise we've hit the next block already.
+    hit = match exit {
+      Some(exit) if blocks.get(exit.block).transparent => {
+        origin += depth;
+        trace(world, blocks, nudge(exit.point(), exit.normal, -1.0), direction)
+      }
+      exit => exit,
+    };
+  }
+
+  (colour, fogged)
+}
+
+/// Render the world to a frame, as seen by a player at `position` (relative to
+/// the monitor) looking through `screen`.
 pub fn render(
   world: &World,
   textures: &Textures,
+  options: &Options,
+  screen: &Screen,
   offset: Vec3<f64>,
   position: Vec3<f64>,
-) -> Buffer {
-  let mut buffer = Buffer::new();
-  buffer
+) -> Frame {
+  let mut frame = Frame::new(screen.width, screen.height);
+  let (width, height) = (frame.width, frame.height);
+  frame
     .as_mut_slice()
-    .par_chunks_exact_mut(BUF_WIDTH as usize)
+    .par_chunks_exact_mut(width as usize)
     .enumerate()
     .for_each(|(y, out)| {
-      for x in 0..BUF_WIDTH {
-        let ox = (1.0 - ((x as f64) / (BUF_WIDTH as f64))) * 8.0;
-        let oy = (1.0 - ((y as f64) / (BUF_HEIGHT as f64))) * 6.0;
-
-        out[x as usize] = match trace(
-          world,
-          Vec3::new(ox + offset.x, oy + offset.y, offset.z),
-          Vec3::new(ox - position.x, oy - position.y, -position.z),
-        ) {
-          None => DEFAULT_COLOUR,
-          Some(hit) => textures.get_colour(hit),
-        }
+      for x in 0..width {
+        let ox = (1.0 - ((x as f64) / (width as f64))) * screen.physical_width;
+        let oy = (1.0 - ((y as f64) / (height as f64))) * screen.physical_height;
+        let point = screen.facing.rotate(Vec3::new(ox, oy, 0.0));
+
+        let start = Vec3::new(point.x + offset.x, point.y + offset.y, point.z + offset.z);
+        let direction = Vec3::new(point.x - position.x, point.y - position.y, point.z - position.z);
+        let (colour, gradient) = colour_ray(world, textures, options, start, direction);
+        // Dither gradients ourselves, so they're smooth even when the frame
+        // isn't dithered.
+        out[x as usize] = if gradient {
+          ordered(colour, textures.palette(), x, y as u32)
+        } else {
+          colour
+        };
       }
     });
-  buffer
+  frame
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// A small xorshift generator, so the test is the same every time it runs.
+  struct Rng(u64);
+
+  impl Rng {
+    fn next(&mut self) -> u64 {
+      self.0 ^= self.0 << 13;
+      self.0 ^= self.0 >> 7;
+      self.0 ^= self.0 << 17;
+      self.0
+    }
+
+    /// A number in `min..max`.
+    fn range(&mut self, min: f64, max: f64) -> f64 {
+      min + (self.next() >> 11) as f64 / (1u64 << 53) as f64 * (max - min)
+    }
+  }
+
+  /// Trace a ray one block at a time, without skipping empty space, returning
+  /// the position and side of the first block for which `stops` returns true.
+  fn trace_one_by_one(
+    world: &World,
+    start: Vec3<f64>,
+    direction: Vec3<f64>,
+    stops: impl Fn(Block) -> bool,
+  ) -> Option<(Vec3<i64>, usize)> {
+    let (width, height, depth) = (world.width as i64, world.height as i64, world.depth as i64);
+    let (mut map_x, step_x, delta_dist_x, mut side_dist_x) = get_dists(start.x, direction.x);
+    let (mut map_y, step_y, delta_dist_y, mut side_dist_y) = get_dists(start.y, direction.y);
+    let (mut map_z, step_z, delta_dist_z, mut side_dist_z) = get_dists(start.z, direction.z);
+
+    loop {
+      let side = if side_dist_x < side_dist_y && side_dist_x < side_dist_z {
+        map_x += step_x;
+        side_dist_x += delta_dist_x;
+        Plane::X
+      } else if side_dist_x >= side_dist_y && side_dist_y < side_dist_z {
+        map_y += step_y;
+        side_dist_y += delta_dist_y;
+        Plane::Y
+      } else {
+        map_z += step_z;
+        side_dist_z += delta_dist_z;
+        Plane::Z
+      };
+
+      if (0..width).contains(&map_x) && (0..height).contains(&map_y) && (0..depth).contains(&map_z)
+      {
+        if stops(world.get(map_x as usize, map_y as usize, map_z as usize)) {
+          return Some((Vec3::new(map_x, map_y, map_z), side as usize));
+        }
+      } else if outside(map_x, 0, width, step_x)
+        || outside(map_y, 0, height, step_y)
+        || outside(map_z, 0, depth, step_z)
+      {
+        return None;
+      }
+    }
+  }
+
+  /// Skipping over empty sections and bricks should never change which block
+  /// a ray hits.
+  #[test]
+  fn skipping_matches_one_by_one() {
+    let blocks = Blocks::load(include_str!("../../blocks.json")).unwrap();
+    let stone = blocks.parse('s').unwrap();
+    let mut rng = Rng(0x2545f4914f6cdd1d);
+
+    // A world with solid, empty and mixed sections, and sections cut off by the
+    // edges of the world.
+    let (width, height, depth) = (50, 37, 45);
+    let mut world = World::new(width, height, depth);
+    for z in 0..depth {
+      for y in 0..height {
+        for x in 0..width {
+          let solid = y < 5 || (x >= 32 && z < 16 && y < 20) || rng.next().is_multiple_of(100);
+          if solid {
+            world.set(x, y, z, stone);
+          }
+        }
+      }
+    }
+    world.compact();
+
+    // Half of the rays start on block boundaries and move in simple directions,
+    // so that they often cross several boundaries at once and the ties between
+    // axes are tested.
+    const STEPS: [f64; 7] = [-2.0, -1.0, -0.5, 0.0, 0.5, 1.0, 2.0];
+    let coordinate = |rng: &mut Rng, size: usize, exact: bool| {
+      let value = rng.range(-10.0, size as f64 + 10.0);
+      if exact {
+        (value * 2.0).floor() / 2.0
+      } else {
+        value
+      }
+    };
+    let axis = |rng: &mut Rng, exact: bool| match (exact, rng.next() % 8) {
+      (true, _) => STEPS[rng.next() as usize % STEPS.len()],
+      (false, 0) => 0.0,
+      (false, _) => rng.range(-1.0, 1.0),
+    };
+
+    for i in 0..200_000 {
+      let exact = i % 2 == 0;
+      let start = Vec3::new(
+        coordinate(&mut rng, width, exact),
+        coordinate(&mut rng, height, exact),
+        coordinate(&mut rng, depth, exact),
+      );
+      let direction =
+        Vec3::new(axis(&mut rng, exact), axis(&mut rng, exact), axis(&mut rng, exact));
+      if direction.x == 0.0 && direction.y == 0.0 && direction.z == 0.0 {
+        continue;
+      }
+
+      let stops = |block| block != Block::AIR;
+      let expected = trace_one_by_one(&world, start, direction, stops);
+      let actual =
+        trace_until(&world, start, direction, stops).map(|hit| (hit.position, hit.side as usize));
+      let key = |hit: Option<(Vec3<i64>, usize)>| hit.map(|(p, side)| (p.x, p.y, p.z, side));
+      assert_eq!(key(actual), key(expected), "ray from {:?} towards {:?}", start, direction);
+    }
+  }
 }
@@ -1,9 +1,15 @@
 //! The various routes served by c33d.
 
 use prometheus::{Encoder, TextEncoder};
+use std::collections::BTreeMap;
+use std::sync::Arc;
 use warp::http::{header::CONTENT_TYPE, Response};
 use warp::hyper::Body;
 
+use crate::cache::{is_content_id, WorldCache};
+use crate::texture::SharedTextures;
+use crate::world::{Grid, World};
+
 /// `GET /metrics`: Exports Prometheus metrics.
 pub fn metrics() -> Response<Body> {
   let encoder = TextEncoder::new();
@@ -18,20 +24,80 @@ pub fn metrics() -> Response<Body> {
     .unwrap()
 }
 
+/// `PUT /worlds/{id}`: Uploads a world, in the same format as the `world` field of the render websocket's initial
+/// message. Sessions can then show this world by sending its id instead. Any sessions already showing a world with
+/// this id are updated.
+pub fn put_world(
+  id: String,
+  world: Grid,
+  textures: Arc<SharedTextures>,
+  cache: Arc<WorldCache>,
+) -> Response<Body> {
+  if is_content_id(&id) {
+    return Response::builder()
+      .status(400)
+      .body(Body::from(format!("World id {:?} is reserved for worlds sent by clients", id)))
+      .unwrap();
+  }
+
+  match World::from_grid(&world, textures.get().blocks(), cache.max_volume()) {
+    Ok(world) => {
+      cache.put(id, world);
+      Response::builder().status(204).body(Body::empty()).unwrap()
+    }
+    Err(err) => Response::builder()
+      .status(400)
+      .body(Body::from(err.to_string()))
+      .unwrap(),
+  }
+}
+
+/// `GET /blocks`: Lists the available blocks, as a JSON object mapping each block's Minecraft id to the character
+/// used for it in worlds. Clients use this to convert the blocks they scan into a world.
+pub fn blocks(textures: Arc<SharedTextures>) -> Response<Body> {
+  let textures = textures.get();
+  let blocks: BTreeMap<&str, char> = textures
+    .blocks()
+    .iter()
+    .map(|(_, info)| (info.id.as_str(), info.key))
+    .collect();
+
+  Response::builder()
+    .status(200)
+    .header(CONTENT_TYPE, "application/json")
+    .body(Body::from(serde_json::to_vec(&blocks).unwrap()))
+    .unwrap()
+}
+
 mod render {
-  use futures_util::{SinkExt, StreamExt};
+  use futures_util::stream::SplitSink;
+  use futures_util::{FutureExt, SinkExt, StreamExt};
   use lazy_static::lazy_static;
-  use log::error;
-  use prometheus::{register_histogram, Histogram};
+  use log::{error, warn};
+  use prometheus::{register_histogram, register_int_counter, Histogram, IntCounter};
   use serde::de::DeserializeOwned;
-  use serde::Deserialize;
+  use serde::{Deserialize, Serialize};
   use std::sync::Arc;
-  use warp::ws::Message;
+  use tokio::sync::watch;
+  use tokio::task::JoinHandle;
+  use warp::ws::{Message, WebSocket};
   use warp::Reply;
 
-  use crate::ray::{render as do_render, Vec3};
-  use crate::texture::Textures;
-  use crate::world::World;
+  use crate::buffer::{delta, MAX_HEIGHT, MAX_WIDTH};
+  use crate::cache::{content_id, is_content_id, SharedWorld, WorldCache};
+  use crate::compression::Compression;
+  use crate::dither::{quantise, Dither};
+  use crate::light::{Lighting, DEFAULT_SUN};
+  use crate::ray::{
+    render as do_render, Facing, Fog, Options, Screen, Vec3, DEFAULT_TRANSLUCENT_DEPTH,
+  };
+  use crate::sky::{Sky, DEFAULT_TIME};
+  use crate::texture::{SharedTextures, Textures};
+  use crate::world::{Edit, Grid, World, WorldError};
+
+  /// The version of the protocol spoken over this websocket. Clients must send
+  /// this in their initial world message.
+  const PROTOCOL_VERSION: u32 = 1;
 
   lazy_static! {
     static ref RENDER_DURATION: Histogram = register_histogram!(
@@ -40,15 +106,70 @@ mod render {
       vec![0.0, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1],
     )
     .unwrap();
+    static ref DROPPED_POSITIONS: IntCounter = register_int_counter!(
+      "r3d_dropped_positions_total",
+      "The number of positions which were never rendered, as a newer one arrived first"
+    )
+    .unwrap();
+    static ref FRAME_BYTES: IntCounter = register_int_counter!(
+      "r3d_frame_bytes_total",
+      "The size of all frames before compression, in bytes"
+    )
+    .unwrap();
+    static ref FRAME_SENT_BYTES: IntCounter = register_int_counter!(
+      "r3d_frame_sent_bytes_total",
+      "The size of all frames after compression, in bytes"
+    )
+    .unwrap();
   }
 
   #[derive(Deserialize)]
   #[serde(rename_all = "camelCase")]
   struct WorldMessage {
-    world: World,
+    /// The world to show. This may be omitted if `world_id` refers to a world
+    /// the server already knows about.
+    world: Option<Grid>,
+    /// The id of a world uploaded with `PUT /worlds/{id}`, or of a world sent
+    /// by an earlier session (see [`ServerMessage::World`]). When both this and
+    /// `world` are given, `world` is only used if the id is unknown. Ids of
+    /// worlds identified by their contents may not be sent with a world.
+    world_id: Option<String>,
     offset_x: f64,
     offset_y: f64,
     offset_z: f64,
+    #[serde(default)]
+    facing: Facing,
+    width: u32,
+    height: u32,
+    physical_width: f64,
+    physical_height: f64,
+    #[serde(default)]
+    dither: Dither,
+    #[serde(default)]
+    compression: Compression,
+    /// The direction of the sun, pointing from the world towards it.
+    #[serde(default)]
+    sun: Option<Vec3<f64>>,
+    /// Whether blocks should cast shadows.
+    #[serde(default)]
+    shadows: bool,
+    /// How far the player can see through translucent blocks, such as water.
+    #[serde(default)]
+    translucent_depth: Option<f64>,
+    /// The time of day, in hours since midnight, which sets the sky's colour.
+    #[serde(default)]
+    time: Option<f64>,
+    /// Whether the sun should be drawn in the sky.
+    #[serde(default)]
+    sun_disc: bool,
+    /// How far from the player blocks start to fade into the sky. Defaults to
+    /// 0, but is only used when `fog_end` is given.
+    #[serde(default)]
+    fog_start: Option<f64>,
+    /// How far from the player blocks are hidden completely by fog. There is no
+    /// fog if this isn't given.
+    #[serde(default)]
+    fog_end: Option<f64>,
   }
 
   #[derive(Deserialize)]
@@ -58,66 +179,464 @@ mod render {
     z: f64,
   }
 
-  fn decode_message<T: DeserializeOwned>(msg: Result<Message, warp::Error>) -> Option<T> {
-    match msg {
-      Ok(msg) => match msg.to_str() {
-        Ok(msg) => match serde_json::from_str(msg) {
-          Ok(result) => Some(result),
-          Err(err) => {
-            error!("Failed to parse message: {}", err);
-            None
-          }
-        },
-        Err(()) => {
-          error!("Failed to parse message: Not a binary message.");
-          None
-        }
-      },
-      Err(e) => {
-        error!("Error in receiving message: {}", e);
-        None
+  /// A list of changes to the session's world, sent as
+  /// `{ "type": "worldPatch", "edits": [...] }`. See [`Edit`] for the format of
+  /// each edit.
+  #[derive(Deserialize)]
+  struct WorldPatch {
+    edits: Vec<Edit>,
+  }
+
+  /// A message sent from the server to the client, as JSON. Rendered frames are
+  /// sent as binary messages instead.
+  #[derive(Serialize)]
+  #[serde(tag = "type", rename_all = "camelCase")]
+  enum ServerMessage {
+    /// The id of the world this session is showing. Clients may send this as
+    /// `worldId` in later sessions, rather than sending the whole world again.
+    ///
+    /// This is sent again if the id changes, which happens when a world
+    /// identified by its contents is patched (see [`WorldCache::apply`]).
+    World { id: String },
+    /// The palette the monitor should use, as a list of 16 `0xRRGGBB` colours.
+    /// This is sent after the initial world message, and then again whenever
+    /// the palette changes.
+    Palette { colours: Vec<u32> },
+    /// An error in a message sent by the client. Errors in the initial world
+    /// message are fatal, and the connection is closed after sending them.
+    Error { code: ErrorCode, message: String },
+  }
+
+  /// The kind of an [`ServerMessage::Error`].
+  #[derive(Serialize, Clone, Copy, Debug)]
+  #[serde(rename_all = "snake_case")]
+  enum ErrorCode {
+    /// The message was not valid JSON, or did not have the expected fields.
+    MalformedMessage,
+    /// The world contains a block the server doesn't know about.
+    UnknownBlock,
+    /// The world id isn't known to the server, and no world was sent.
+    UnknownWorld,
+    /// The world is empty, not rectangular, too large, or could not be decoded,
+    /// or a patch to it extends outside the world.
+    InvalidWorld,
+    /// The monitor's size is invalid.
+    BadDimensions,
+    /// The client uses a different version of the protocol to the server.
+    UnsupportedVersion,
+  }
+
+  /// An error in a message sent by the client, which should be reported back to
+  /// it.
+  struct ClientError {
+    code: ErrorCode,
+    message: String,
+  }
+
+  impl ClientError {
+    fn new(code: ErrorCode, message: impl Into<String>) -> ClientError {
+      ClientError { code, message: message.into() }
+    }
+  }
+
+  async fn send_error(send: &mut SplitSink<WebSocket, Message>, err: ClientError) {
+    warn!("Error in client message: {:?}: {}", err.code, err.message);
+    send_message(send, &ServerMessage::Error { code: err.code, message: err.message }).await;
+  }
+
+  async fn send_message(send: &mut SplitSink<WebSocket, Message>, message: &ServerMessage) {
+    let message = serde_json::to_string(message).unwrap();
+    if let Err(err) = send.send(Message::text(message)).await {
+      error!("Error sending message: {}", err);
+    }
+  }
+
+  fn decode_message<T: DeserializeOwned>(message: &Message) -> Result<T, ClientError> {
+    let message = message
+      .to_str()
+      .map_err(|()| ClientError::new(ErrorCode::MalformedMessage, "Expected a text message"))?;
+    serde_json::from_str(message)
+      .map_err(|err| ClientError::new(ErrorCode::MalformedMessage, err.to_string()))
+  }
+
+  fn decode_value<T: DeserializeOwned>(value: serde_json::Value) -> Result<T, ClientError> {
+    serde_json::from_value(value)
+      .map_err(|err| ClientError::new(ErrorCode::MalformedMessage, err.to_string()))
+  }
+
+  fn world_error(err: WorldError) -> ClientError {
+    let code = match err {
+      WorldError::UnknownBlock { .. } => ErrorCode::UnknownBlock,
+      _ => ErrorCode::InvalidWorld,
+    };
+    ClientError::new(code, err.to_string())
+  }
+
+  /// Messages received from the client which haven't been handled yet.
+  #[derive(Default)]
+  struct Pending {
+    /// The latest position to render. This replaces any position which hasn't
+    /// been rendered yet.
+    position: Option<RenderMessage>,
+    /// Patches to apply to the world before the next frame. Each patch is
+    /// applied (or rejected) as a whole.
+    patches: Vec<WorldPatch>,
+  }
+
+  /// Queue a message from the client (either a position or a world patch) to be
+  /// handled before the next frame.
+  fn queue_message(pending: &mut Pending, message: Message) -> Result<(), ClientError> {
+    if message.is_close() || message.is_ping() || message.is_pong() {
+      return Ok(());
+    }
+
+    let value: serde_json::Value = decode_message(&message)?;
+    if value.get("type").and_then(|kind| kind.as_str()) == Some("worldPatch") {
+      pending.patches.push(decode_value(value)?);
+    } else {
+      let position: RenderMessage = decode_value(value)?;
+      if pending.position.replace(position).is_some() {
+        DROPPED_POSITIONS.inc();
       }
     }
+    Ok(())
   }
 
-  async fn websocket_handler(websocket: warp::ws::WebSocket, textures: Arc<Textures>) {
-    let (mut send, mut receive) = websocket.split();
+  /// A session's subscription to a world in the [`WorldCache`].
+  struct Subscription {
+    id: String,
+    world: Arc<SharedWorld>,
+    updates: watch::Receiver<Arc<World>>,
+  }
 
-    let world = if let Some(world) = receive
-      .next()
-      .await
-      .and_then(decode_message::<WorldMessage>)
-    {
-      world
+  /// Split a binary initial message into its JSON header and the world, in the
+  /// form described by [`World::from_binary`]. The header is prefixed by its
+  /// length, as a big-endian `u32`.
+  fn split_binary(data: &[u8]) -> Result<(serde_json::Value, &[u8]), ClientError> {
+    let truncated = || ClientError::new(ErrorCode::MalformedMessage, "Binary message is truncated");
+    let length = data.get(..4).ok_or_else(truncated)?;
+    let length = u32::from_be_bytes(length.try_into().unwrap()) as usize;
+    let header = data[4..].get(..length).ok_or_else(truncated)?;
+    let value = serde_json::from_slice(header)
+      .map_err(|err| ClientError::new(ErrorCode::MalformedMessage, err.to_string()))?;
+    Ok((value, &data[4 + length..]))
+  }
+
+  /// Parse and validate the initial world message, returning the message, the
+  /// world to show, and the screen to render to.
+  ///
+  /// This is normally a JSON message, but may instead be a binary message
+  /// containing a compact encoding of the world (see [`split_binary`]).
+  fn handshake(
+    message: &Message,
+    textures: &Textures,
+    cache: &WorldCache,
+  ) -> Result<(WorldMessage, Subscription, Screen, Options), ClientError> {
+    let (value, binary) = if message.is_binary() {
+      let (value, world) = split_binary(message.as_bytes())?;
+      (value, Some(world).filter(|world| !world.is_empty()))
     } else {
-      return;
+      (decode_message(message)?, None)
     };
+    let version = value.get("version").and_then(|version| version.as_u64());
+    if version != Some(PROTOCOL_VERSION as u64) {
+      return Err(ClientError::new(
+        ErrorCode::UnsupportedVersion,
+        format!("Expected protocol version {}, got {:?}", PROTOCOL_VERSION, version),
+      ));
+    }
 
-    while let Some(message) = receive.next().await {
-      if let Some(position) = decode_message::<RenderMessage>(message) {
-        let timer = RENDER_DURATION.start_timer();
+    let mut world: WorldMessage = decode_value(value)?;
 
-        let buffer = do_render(
-          &world.world,
-          &textures,
-          Vec3::new(world.offset_x, world.offset_y, world.offset_z),
-          Vec3::new(position.x, position.y, position.z),
-        );
-        let result = buffer.draw();
+    if !(1..=MAX_WIDTH).contains(&world.width) || !(1..=MAX_HEIGHT).contains(&world.height) {
+      return Err(ClientError::new(
+        ErrorCode::BadDimensions,
+        format!(
+          "Invalid monitor size {}x{} (should be at most {}x{})",
+          world.width, world.height, MAX_WIDTH, MAX_HEIGHT
+        ),
+      ));
+    }
 
-        timer.observe_duration();
+    let valid_size = |size: f64| size > 0.0 && size.is_finite();
+    if !valid_size(world.physical_width) || !valid_size(world.physical_height) {
+      return Err(ClientError::new(
+        ErrorCode::BadDimensions,
+        format!("Invalid physical monitor size {}x{}", world.physical_width, world.physical_height),
+      ));
+    }
+
+    let lighting =
+      Lighting::new(world.sun.unwrap_or(DEFAULT_SUN), world.shadows).ok_or_else(|| {
+        ClientError::new(ErrorCode::MalformedMessage, "The sun's direction should not be zero")
+      })?;
 
-        if let Err(err) = send.send(Message::binary(result)).await {
-          error!("Error sending message: {}", err);
+    let translucent_depth = world.translucent_depth.unwrap_or(DEFAULT_TRANSLUCENT_DEPTH);
+    if !(translucent_depth > 0.0 && translucent_depth.is_finite()) {
+      return Err(ClientError::new(
+        ErrorCode::MalformedMessage,
+        format!("Invalid translucent depth {}", translucent_depth),
+      ));
+    }
+
+    let time = world.time.unwrap_or(DEFAULT_TIME);
+    if !time.is_finite() {
+      return Err(ClientError::new(
+        ErrorCode::MalformedMessage,
+        format!("Invalid time of day {}", time),
+      ));
+    }
+    let sky = Sky::new(time, world.sun_disc.then(|| lighting.sun()));
+
+    let fog = world
+      .fog_end
+      .map(|end| Fog { start: world.fog_start.unwrap_or(0.0), end });
+    if let Some(Fog { start, end }) = fog {
+      if !(start >= 0.0 && start < end && end.is_finite()) {
+        return Err(ClientError::new(
+          ErrorCode::MalformedMessage,
+          format!("Invalid fog distances {} to {}", start, end),
+        ));
+      }
+    }
+
+    let options = Options { lighting, sky, translucent_depth, fog };
+
+    let grid = world.world.take();
+    let content_addressed = world.world_id.is_none();
+    let id = match (world.world_id.take(), &grid, binary) {
+      (_, Some(_), Some(_)) => {
+        return Err(ClientError::new(
+          ErrorCode::MalformedMessage,
+          "Binary messages should not have a world field",
+        ))
+      }
+      // Content ids must match the world they were computed from, so clients
+      // may show existing worlds with them, but never send a world for one.
+      (Some(id), Some(_), _) | (Some(id), _, Some(_)) if is_content_id(&id) => {
+        return Err(ClientError::new(
+          ErrorCode::MalformedMessage,
+          format!("World id {:?} is reserved for worlds identified by their contents", id),
+        ))
+      }
+      (Some(id), _, _) => id,
+      (None, Some(grid), None) => content_id(&serde_json::to_vec(grid).unwrap()),
+      (None, None, Some(binary)) => content_id(binary),
+      (None, None, None) => {
+        return Err(ClientError::new(ErrorCode::MalformedMessage, "Expected a world or worldId"))
+      }
+    };
+
+    let (shared, updates) = match cache.subscribe(&id) {
+      Some(subscription) => subscription,
+      None => {
+        let world = match (&grid, binary) {
+          (Some(grid), _) => World::from_grid(grid, textures.blocks(), cache.max_volume()),
+          (None, Some(binary)) => World::from_binary(binary, textures.blocks(), cache.max_volume()),
+          (None, None) => {
+            let message = format!("Unknown world {:?}", id);
+            return Err(ClientError::new(ErrorCode::UnknownWorld, message));
+          }
+        };
+        cache.insert(&id, world.map_err(world_error)?, content_addressed)
+      }
+    };
+
+    let screen = Screen {
+      facing: world.facing,
+      width: world.width,
+      height: world.height,
+      physical_width: world.physical_width,
+      physical_height: world.physical_height,
+    };
+
+    Ok((world, Subscription { id, world: shared, updates }, screen, options))
+  }
+
+  async fn websocket_handler(
+    websocket: WebSocket,
+    textures: Arc<SharedTextures>,
+    cache: Arc<WorldCache>,
+  ) {
+    let (mut send, mut receive) = websocket.split();
+
+    let message = match receive.next().await {
+      None => return,
+      Some(Err(err)) => {
+        error!("Error in receiving message: {}", err);
+        return;
+      }
+      Some(Ok(message)) => message,
+    };
+
+    // Decoding the world may take a while, so do it off the async worker
+    // threads.
+    let result = {
+      let (textures, cache) = (textures.get(), cache.clone());
+      tokio::task::spawn_blocking(move || handshake(&message, &textures, &cache)).await
+    };
+    let (world, mut subscription, screen, options) = match result.unwrap() {
+      Ok(result) => result,
+      Err(err) => {
+        send_error(&mut send, err).await;
+        let _ = send.close().await;
+        return;
+      }
+    };
+    let offset = Vec3::new(world.offset_x, world.offset_y, world.offset_z);
+
+    // The last frame we sent, so we only need to send the lines which changed.
+    let mut last_frame: Option<Vec<u8>> = None;
+
+    send_message(&mut send, &ServerMessage::World { id: subscription.id.clone() }).await;
+
+    let mut palette = textures.get().palette().to_hex();
+    send_message(&mut send, &ServerMessage::Palette { colours: palette.clone() }).await;
+
+    // The messages we've received but not handled yet, and the frame currently
+    // being rendered. We only render one frame at a time, and if the client
+    // sends positions faster than we can render them, all but the latest are
+    // dropped.
+    let mut pending = Pending::default();
+    let mut rendering: Option<JoinHandle<Vec<u8>>> = None;
+
+    // The last position we rendered, so we can redraw it when the world changes.
+    let mut last_position: Option<Vec3<f64>> = None;
+    let mut world_changed = false;
+
+    loop {
+      tokio::select! {
+        message = receive.next() => match message {
+          None => break,
+          Some(Err(err)) => {
+            error!("Error in receiving message: {}", err);
+            break;
+          }
+          Some(Ok(message)) => {
+            if let Err(err) = queue_message(&mut pending, message) {
+              send_error(&mut send, err).await;
+            }
+          }
+        },
+        // The world was edited, either by this session or another one showing
+        // the same world.
+        result = subscription.updates.changed() => match result {
+          Ok(()) => world_changed = true,
+          Err(_) => break,
+        },
+        result = async { rendering.as_mut().unwrap().await }, if rendering.is_some() => {
+          rendering = None;
+          let result = match result {
+            Ok(result) => result,
+            Err(err) => {
+              error!("Error rendering frame: {}", err);
+              continue;
+            }
+          };
+
+          let message = delta(last_frame.as_deref(), &result, screen.width);
+          last_frame = Some(result);
+          if !message.is_empty() {
+            FRAME_BYTES.inc_by(message.len() as u64);
+            let message = world.compression.compress(message);
+            FRAME_SENT_BYTES.inc_by(message.len() as u64);
+
+            if let Err(err) = send.send(Message::binary(message)).await {
+              error!("Error sending message: {}", err);
+            }
+          }
+        }
+      }
+
+      if rendering.is_some() {
+        continue;
+      }
+
+      // Positions may have arrived while we were rendering or sending the last
+      // frame, so make sure we've seen all of them before picking one.
+      while let Some(Some(Ok(message))) = receive.next().now_or_never() {
+        if let Err(err) = queue_message(&mut pending, message) {
+          send_error(&mut send, err).await;
+        }
+      }
+
+      let textures = textures.get();
+
+      for patch in std::mem::take(&mut pending.patches) {
+        let result = {
+          let (id, shared) = (subscription.id.clone(), subscription.world.clone());
+          let (textures, cache) = (textures.clone(), cache.clone());
+          tokio::task::spawn_blocking(move || {
+            cache.apply(&id, &shared, &patch.edits, textures.blocks())
+          })
+          .await
+        };
+        match result.unwrap() {
+          Ok(None) => world_changed = true,
+          Ok(Some((id, (world, updates)))) => {
+            // We edited a copy of the world, so switch over to it.
+            let old = std::mem::replace(&mut subscription, Subscription { id, world, updates });
+            drop(old.updates);
+            cache.release(&old.id);
+
+            send_message(&mut send, &ServerMessage::World { id: subscription.id.clone() }).await;
+            world_changed = true;
+          }
+          Err(err) => send_error(&mut send, world_error(err)).await,
         }
       }
+
+      let position = match (pending.position.take(), last_position) {
+        (Some(position), _) => Vec3::new(position.x, position.y, position.z),
+        (None, Some(position)) if world_changed => position,
+        (None, _) => continue,
+      };
+      last_position = Some(position);
+      world_changed = false;
+
+      // Textures may have been reloaded since the last frame, so let the client
+      // know if its palette is out of date.
+      let new_palette = textures.palette().to_hex();
+      if new_palette != palette {
+        palette = new_palette;
+        send_message(&mut send, &ServerMessage::Palette { colours: palette.clone() }).await;
+
+        // The client clears the monitor when the palette changes, so redraw
+        // everything.
+        last_frame = None;
+      }
+
+      // Rendering is CPU-bound (and uses rayon internally), so run it off the
+      // async worker threads.
+      let contents = subscription.updates.borrow_and_update().clone();
+      let dither = world.dither;
+      rendering = Some(tokio::task::spawn_blocking(move || {
+        let timer = RENDER_DURATION.start_timer();
+        let frame = do_render(&contents, &textures, &options, &screen, offset, position);
+        let buffer = quantise(&frame, textures.palette(), dither);
+        let result = buffer.draw(textures.palette());
+        timer.observe_duration();
+        result
+      }));
     }
+
+    let Subscription { id, updates, .. } = subscription;
+    drop(updates);
+    cache.release(&id);
   }
 
   /// `GET /render`: Serves a websocket which accepts messages of the form `{ x: f64, y: f64, z: f64 }` and responds
-  /// with the rendered world.
-  pub fn render(ws: warp::ws::Ws, textures: Arc<Textures>) -> impl Reply {
-    ws.on_upgrade(move |websocket| websocket_handler(websocket, textures))
+  /// with the lines of the rendered world which have changed (see [`crate::buffer::delta`]). The palette to render
+  /// with is sent as a separate JSON message.
+  ///
+  /// The world may be edited during the session by sending a `worldPatch` message (see [`WorldPatch`]). Worlds are
+  /// shared between every session showing them (see [`WorldCache`]), so edits are seen by all of them.
+  pub fn render(
+    ws: warp::ws::Ws,
+    textures: Arc<SharedTextures>,
+    cache: Arc<WorldCache>,
+  ) -> impl Reply {
+    ws.on_upgrade(move |websocket| websocket_handler(websocket, textures, cache))
   }
 }
 
@@ -0,0 +1,102 @@
+//! The sky, drawn wherever a ray doesn't hit anything. This fades from the
+//! horizon to the zenith, changes colour with the time of day, and may show the
+//! sun.
+
+use crate::palette::Rgb;
+use crate::ray::Vec3;
+use crate::texture::DEFAULT_COLOUR;
+
+/// The time of day used when a session doesn't specify one, in hours.
+pub const DEFAULT_TIME: f64 = 12.0;
+
+/// The colour of the sky at the horizon and at the zenith, at different points
+/// in the day.
+const NIGHT: (Rgb, Rgb) = (Rgb::from_hex(0x24304f), Rgb::from_hex(0x0b1026));
+const TWILIGHT: (Rgb, Rgb) = (Rgb::from_hex(0xee9a5a), Rgb::from_hex(0x4a64a0));
+const DAY: (Rgb, Rgb) = (Rgb::from_hex(0xaecbf0), DEFAULT_COLOUR);
+
+/// The colours of the sky through the day, keyed by the hour. The sky is
+/// blended between these at other times.
+const HOURS: [(f64, (Rgb, Rgb)); 8] = [
+  (0.0, NIGHT),
+  (5.0, NIGHT),
+  (6.5, TWILIGHT),
+  (8.0, DAY),
+  (16.5, DAY),
+  (18.0, TWILIGHT),
+  (19.5, NIGHT),
+  (24.0, NIGHT),
+];
+
+const SUN_COLOUR: Rgb = Rgb::from_hex(0xfff4c8);
+
+/// How close a ray must be to the sun to hit it, as the cosine of the angle
+/// between them. This gives the sun a radius of about 4 degrees.
+const SUN_SIZE: f64 = 0.9975;
+
+/// Every colour the sky may be blended from. These are included in each
+/// palette, so that the sky can be drawn at any time of day.
+pub const COLOURS: [Rgb; 7] = [
+  NIGHT.0, NIGHT.1, TWILIGHT.0, TWILIGHT.1, DAY.0, DAY.1, SUN_COLOUR,
+];
+
+fn lerp(a: Rgb, b: Rgb, t: f32) -> Rgb {
+  a + (b - a) * t
+}
+
+/// The sky at a particular time of day.
+#[derive(Copy, Clone, Debug)]
+pub struct Sky {
+  horizon: Rgb,
+  zenith: Rgb,
+  /// The direction towards the sun, if it should be drawn.
+  sun: Option<Vec3<f64>>,
+}
+
+impl Sky {
+  /// Create the sky at a time of day, in hours since midnight. Times outside a
+  /// single day are wrapped around.
+  ///
+  /// If `sun` is given, a disc is drawn in that (normalised) direction. This
+  /// should usually be the same direction the world is lit from.
+  pub fn new(time: f64, sun: Option<Vec3<f64>>) -> Sky {
+    let time = time.rem_euclid(24.0);
+    let next = HOURS
+      .iter()
+      .position(|(hour, _)| *hour > time)
+      .unwrap_or(HOURS.len() - 1);
+    let (start, (start_horizon, start_zenith)) = HOURS[next - 1];
+    let (end, (end_horizon, end_zenith)) = HOURS[next];
+
+    let t = ((time - start) / (end - start)) as f32;
+    Sky {
+      horizon: lerp(start_horizon, end_horizon, t),
+      zenith: lerp(start_zenith, end_zenith, t),
+      sun,
+    }
+  }
+
+  /// The colour of the sky in a particular direction.
+  pub fn colour(&self, direction: Vec3<f64>) -> Rgb {
+    let length =
+      (direction.x * direction.x + direction.y * direction.y + direction.z * direction.z).sqrt();
+    let elevation = (direction.y / length).max(0.0);
+
+    if let Some(sun) = self.sun {
+      let angle = (direction.x * sun.x + direction.y * sun.y + direction.z * sun.z) / length;
+      if elevation > 0.0 && angle > SUN_SIZE {
+        return SUN_COLOUR;
+      }
+    }
+
+    // Most of the sky is close to the colour of the zenith, with the colour of
+    // the horizon only showing low down.
+    lerp(self.horizon, self.zenith, elevation.sqrt() as f32)
+  }
+}
+
+impl Default for Sky {
+  fn default() -> Sky {
+    Sky::new(DEFAULT_TIME, None)
+  }
+}
@@ -1,46 +1,27 @@
 //! Texture loading and mapping for blocks.
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
 use tinybmp::RawBmp;
 
-use crate::buffer::Colour;
-use crate::ray::{Hit, Plane};
-use crate::world::Block;
+use crate::light::SHADES;
+use crate::palette::{Palette, Rgb};
+use crate::ray::Hit;
+use crate::sky;
+use crate::world::{Blocks, Faces};
 
 const WIDTH: usize = 8;
 const HEIGHT: usize = 8;
 
-type Image = Vec<Colour>;
+type Image = Vec<Rgb>;
 
 /// The default "background" colour, used when no blocks are "under" that pixel
 /// and so open sky should be shown instead.
 ///
 /// > Do you love the colour of the sky?
-pub const DEFAULT_COLOUR: Colour = 9;
-
-fn get_colour(c: u32) -> Result<Colour> {
-  match c {
-    // White
-    0xf0f0f0 => Ok(0),
-    // Green
-    0x73b349 => Ok(1),
-    0x5f9f35 => Ok(2),
-    0x509026 => Ok(3),
-    // Brown
-    0x966c4a => Ok(4),
-    0x79553a => Ok(5),
-    0x593d29 => Ok(6),
-    // Blue
-    0x3266cc => Ok(7),
-    0x4c32cc => Ok(8),
-    // Grey
-    0x8f8f8f => Ok(10),
-    0x747474 => Ok(11),
-    0x686868 => Ok(12),
-
-    c => Err(anyhow!("Unknown colour {}", c)),
-  }
-}
+pub const DEFAULT_COLOUR: Rgb = Rgb::from_hex(0x6495ed);
 
 fn load_texture(bytes: &[u8]) -> Result<Image> {
   let bitmap = match RawBmp::from_slice(bytes) {
@@ -59,64 +40,130 @@ fn load_texture(bytes: &[u8]) -> Result<Image> {
     ));
   };
 
-  let mut pixels = vec![0; WIDTH * HEIGHT];
+  let mut pixels = vec![DEFAULT_COLOUR; WIDTH * HEIGHT];
   for pixel in bitmap.pixels() {
-    let colour = get_colour(pixel.color)?;
-    pixels[(pixel.position.x as usize) + (pixel.position.y as usize) * WIDTH] = colour;
+    pixels[(pixel.position.x as usize) + (pixel.position.y as usize) * WIDTH] =
+      Rgb::from_hex(pixel.color);
   }
 
   Ok(pixels)
 }
 
-/// All textures loaded by the game. Typically each block has three textures, one
-/// for each axis.
+/// The textures built in to the server, and the names blocks refer to them by.
+const BUILTIN: &[(&str, &[u8])] = &[
+  ("water", include_bytes!("../../texture/water.bmp")),
+  ("dirt", include_bytes!("../../texture/dirt.bmp")),
+  ("grass_side", include_bytes!("../../texture/grass_side.bmp")),
+  ("grass_top", include_bytes!("../../texture/grass_top.bmp")),
+  ("stone", include_bytes!("../../texture/stone.bmp")),
+];
+
+/// A directory of textures, which override the built-in ones.
 ///
-///  - Top: This is the brightest of the three textures. We assume the bottom of
-///    a block is never visible, hence (right now at least) not having a separate
-///    texture for the bottom.
-///  - Front/Back (z-axis): Slightly dimmer than the top.
-///  - Left/Right (x-axis): The darkest of the three textures.
-pub struct Textures {
-  water: Image,
+/// The directory should contain a `textures.json` manifest, mapping texture
+/// names to image files (relative to the directory). Any textures not listed in
+/// the manifest fall back to the built-in ones.
+pub struct TexturePack {
+  dir: PathBuf,
+  manifest: HashMap<String, PathBuf>,
+}
 
-  dirt_x: Image,
-  dirt_y: Image,
-  dirt_z: Image,
+impl TexturePack {
+  /// Load a texture pack's manifest.
+  pub fn open(dir: &Path) -> Result<TexturePack> {
+    let manifest_path = dir.join("textures.json");
+    let manifest = std::fs::read_to_string(&manifest_path)
+      .map_err(anyhow::Error::from)
+      .and_then(|contents| Ok(serde_json::from_str(&contents)?))
+      .with_context(|| format!("Failed to load {}", manifest_path.display()))?;
 
-  grass_x: Image,
-  grass_y: Image,
-  grass_z: Image,
+    Ok(TexturePack { dir: dir.to_path_buf(), manifest })
+  }
 
-  stone_x: Image,
-  stone_y: Image,
-  stone_z: Image,
+  /// The manifest of the pack at `dir`, and every file it refers to. If the
+  /// manifest can't be read, this is just the manifest itself.
+  pub fn files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = vec![dir.join("textures.json")];
+    if let Ok(pack) = TexturePack::open(dir) {
+      files.extend(pack.manifest.values().map(|file| pack.dir.join(file)));
+    }
+    files
+  }
+}
+
+fn load_named(name: &str, pack: Option<&TexturePack>) -> Result<Image> {
+  if let Some(file) = pack.and_then(|pack| pack.manifest.get(name).map(|file| pack.dir.join(file)))
+  {
+    return std::fs::read(&file)
+      .map_err(anyhow::Error::from)
+      .and_then(|bytes| load_texture(&bytes))
+      .with_context(|| format!("Failed to load texture {} from {}", name, file.display()));
+  }
+
+  match BUILTIN.iter().find(|(builtin, _)| *builtin == name) {
+    None => Err(anyhow!("Unknown texture {}", name)),
+    Some((_, bytes)) => {
+      load_texture(bytes).with_context(|| format!("Failed to load texture {}", name))
+    }
+  }
+}
+
+/// All textures loaded by the game, along with the blocks they belong to.
+///
+/// Each block has either a single texture, or one texture for each axis (such
+/// as grass, whose top differs from its sides). Textures should be drawn at
+/// full brightness, as they are shaded when rendering (see [`crate::light`]).
+pub struct Textures {
+  blocks: Blocks,
+  /// The texture for each block, indexed by [`Plane`](crate::ray::Plane). This is [`None`] for
+  /// blocks with no textures (such as air).
+  faces: Vec<Option<[Image; 3]>>,
+  palette: Palette,
 }
 
 impl Textures {
-  /// Load all textures.
-  pub fn new() -> Result<Textures> {
-    let textures = Textures {
-      water: load_texture(include_bytes!("../../texture/water.bmp"))?,
-
-      dirt_x: load_texture(include_bytes!("../../texture/dirt_x.bmp"))?,
-      dirt_y: load_texture(include_bytes!("../../texture/dirt_y.bmp"))?,
-      dirt_z: load_texture(include_bytes!("../../texture/dirt_z.bmp"))?,
-
-      grass_x: load_texture(include_bytes!("../../texture/grass_x.bmp"))?,
-      grass_y: load_texture(include_bytes!("../../texture/grass_top.bmp"))?,
-      grass_z: load_texture(include_bytes!("../../texture/grass_z.bmp"))?,
-
-      stone_x: load_texture(include_bytes!("../../texture/stone_x.bmp"))?,
-      stone_y: load_texture(include_bytes!("../../texture/stone_y.bmp"))?,
-      stone_z: load_texture(include_bytes!("../../texture/stone_z.bmp"))?,
-    };
-    Ok(textures)
+  /// Load the textures for every block in the registry, preferring those in
+  /// `pack` over the built-in ones.
+  pub fn new(blocks: Blocks, pack: Option<&TexturePack>) -> Result<Textures> {
+    let mut faces = Vec::new();
+    for (_, block) in blocks.iter() {
+      faces.push(match &block.textures {
+        None => None,
+        Some(Faces::All(name)) => {
+          let texture = load_named(name, pack)?;
+          Some([texture.clone(), texture.clone(), texture])
+        }
+        Some(Faces::Axis { x, y, z }) => Some([
+          load_named(x, pack)?,
+          load_named(y, pack)?,
+          load_named(z, pack)?,
+        ]),
+      });
+    }
+
+    let colours = faces.iter().flatten().flatten().flatten().copied();
+    let shaded = colours.flat_map(|colour| SHADES.iter().map(move |shade| colour * *shade));
+    // The sky's colours include DEFAULT_COLOUR, so blocks without a texture can
+    // still be drawn.
+    let palette = Palette::quantise(shaded.chain(sky::COLOURS));
+
+    Ok(Textures { blocks, faces, palette })
+  }
+
+  /// The blocks these textures were loaded for.
+  pub fn blocks(&self) -> &Blocks {
+    &self.blocks
+  }
+
+  /// The palette which best represents these textures.
+  pub fn palette(&self) -> &Palette {
+    &self.palette
   }
 
   /// Get the colour under a particular ray trace collision. This looks up the
   /// block and axis to find the texture, and then maps that to a pixel within
   /// the texture.
-  pub fn get_colour(&self, hit: Hit) -> Colour {
+  pub fn get_colour(&self, hit: &Hit) -> Rgb {
     let (x, y) = hit.offset;
     debug_assert!((0.0..=1.0).contains(&x) && (0.0..=1.0).contains(&y));
 
@@ -124,21 +171,40 @@ impl Textures {
     let y = ((y * (HEIGHT as f64)).floor() as usize).clamp(0, HEIGHT - 1);
     let idx = x + y * WIDTH;
 
-    match (hit.block, hit.side) {
-      (Block::Air, _) => DEFAULT_COLOUR,
-      (Block::Water, _) => self.water[idx],
+    match &self.faces[hit.block.index()] {
+      None => DEFAULT_COLOUR,
+      Some(faces) => faces[hit.side as usize][idx],
+    }
+  }
+}
 
-      (Block::Dirt, Plane::X) => self.dirt_x[idx],
-      (Block::Dirt, Plane::Y) => self.dirt_y[idx],
-      (Block::Dirt, Plane::Z) => self.dirt_z[idx],
+/// A set of [`Textures`] which may be replaced while the server is running (see
+/// [`crate::watch`]).
+///
+/// Each frame should call [`SharedTextures::get`] once, and use those textures
+/// for the whole frame.
+///
+/// Worlds refer to blocks by their index in the registry, so the registry may
+/// only grow: new blocks must be added to the end of the file (see
+/// [`Blocks::extends`]).
+pub struct SharedTextures(RwLock<Arc<Textures>>);
+
+impl SharedTextures {
+  pub fn new(textures: Textures) -> SharedTextures {
+    SharedTextures(RwLock::new(Arc::new(textures)))
+  }
 
-      (Block::Grass, Plane::X) => self.grass_x[idx],
-      (Block::Grass, Plane::Y) => self.grass_y[idx],
-      (Block::Grass, Plane::Z) => self.grass_z[idx],
+  /// Get the current set of textures.
+  pub fn get(&self) -> Arc<Textures> {
+    self.0.read().unwrap().clone()
+  }
 
-      (Block::Stone, Plane::X) => self.stone_x[idx],
-      (Block::Stone, Plane::Y) => self.stone_y[idx],
-      (Block::Stone, Plane::Z) => self.stone_z[idx],
-    }
+  /// Replace the current set of textures. This fails (keeping the current
+  /// textures) if the new block registry doesn't extend the current one.
+  pub fn set(&self, textures: Textures) -> Result<()> {
+    let mut current = self.0.write().unwrap();
+    textures.blocks().extends(current.blocks())?;
+    *current = Arc::new(textures);
+    Ok(())
   }
 }
@@ -0,0 +1,68 @@
+//! Reloads textures and block definitions when they change on disk.
+
+use anyhow::Result;
+use log::{error, info};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::texture::{SharedTextures, TexturePack, Textures};
+
+/// How often to check for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A snapshot of the files we're watching, used to detect when they change.
+type Fingerprint = Vec<(PathBuf, Option<SystemTime>, u64)>;
+
+fn fingerprint(paths: &[PathBuf]) -> Fingerprint {
+  let mut files = Vec::new();
+  for path in paths {
+    if path.is_dir() {
+      files.extend(TexturePack::files(path));
+    } else {
+      files.push(path.clone());
+    }
+  }
+  files.sort();
+
+  files
+    .into_iter()
+    .map(|file| match std::fs::metadata(&file) {
+      Ok(metadata) => {
+        let modified = metadata.modified().ok();
+        (file, modified, metadata.len())
+      }
+      Err(_) => (file, None, 0),
+    })
+    .collect()
+}
+
+/// Spawn a thread which polls `paths` (either files or texture pack
+/// directories, where we watch the manifest and the files it names) for
+/// changes, calling `load` to rebuild the textures whenever they change.
+///
+/// If loading fails, or the new block registry removes or reorders any blocks
+/// (see [`SharedTextures::set`]), the error is logged and the existing textures
+/// are kept.
+pub fn watch<F>(paths: Vec<PathBuf>, textures: Arc<SharedTextures>, load: F)
+where
+  F: Fn() -> Result<Textures> + Send + 'static,
+{
+  std::thread::spawn(move || {
+    let mut last = fingerprint(&paths);
+    loop {
+      std::thread::sleep(POLL_INTERVAL);
+
+      let current = fingerprint(&paths);
+      if current == last {
+        continue;
+      }
+      last = current;
+
+      match load().and_then(|new| textures.set(new)) {
+        Ok(()) => info!("Reloaded textures"),
+        Err(err) => error!("Failed to reload textures, keeping the old ones: {:?}", err),
+      }
+    }
+  });
+}
@@ -1,89 +1,743 @@
 //! Defines the available blocks and a "world" containing those blocks.
 
-use serde::de::Error;
-use serde::{Deserialize, Deserializer};
-
-/// A block in the world.
-#[derive(Copy, Clone)]
-pub enum Block {
-  Air,
-  Dirt,
-  Grass,
-  Stone,
-  Water,
-}
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// A block in the world. This is an index into a [`Blocks`] registry.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Block(u16);
 
 impl Block {
+  /// Air is always the first block in the registry.
+  pub const AIR: Block = Block(0);
+
+  /// The index of this block within the registry.
+  pub fn index(self) -> usize {
+    self.0 as usize
+  }
+}
+
+/// The textures used for each face of a block, either the same texture for
+/// every face or one texture per axis.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum Faces {
+  All(String),
+  Axis { x: String, y: String, z: String },
+}
+
+/// The definition of a single block.
+#[derive(Deserialize)]
+pub struct BlockInfo {
+  /// The Minecraft id of this block, such as `minecraft:stone`.
+  pub id: String,
+  /// The character used to represent this block when deserialising a world.
+  pub key: char,
+  /// The textures for this block. Only transparent blocks may omit these.
+  #[serde(default)]
+  pub textures: Option<Faces>,
+  /// Whether rays pass straight through this block.
+  #[serde(default)]
+  pub transparent: bool,
+  /// Whether rays pass through this block (such as water or glass), tinted by
+  /// its texture.
+  #[serde(default)]
+  pub translucent: bool,
+}
+
+impl BlockInfo {
+  /// Whether this block hides everything behind it.
+  pub fn is_opaque(&self) -> bool {
+    !self.transparent && !self.translucent
+  }
+}
+
+/// A registry of all available blocks, loaded from a JSON file (see
+/// `blocks.json` in the repository root).
+///
+/// Air is always defined (as [`Block::AIR`], with the key `' '`), and so should
+/// not be included in the file.
+pub struct Blocks {
+  blocks: Vec<BlockInfo>,
+  keys: HashMap<char, Block>,
+}
+
+impl Blocks {
+  /// Load the block registry from its JSON definition.
+  pub fn load(contents: &str) -> Result<Blocks> {
+    let definitions: Vec<BlockInfo> = serde_json::from_str(contents)?;
+
+    let mut blocks =
+      Blocks { blocks: Vec::with_capacity(definitions.len() + 1), keys: HashMap::new() };
+    blocks.add(BlockInfo {
+      id: "minecraft:air".to_string(),
+      key: ' ',
+      textures: None,
+      transparent: true,
+      translucent: false,
+    })?;
+    for block in definitions {
+      if block.textures.is_none() && !block.transparent {
+        return Err(anyhow!("Block {} has no textures, but is not transparent", block.id));
+      }
+      if block.transparent && block.translucent {
+        return Err(anyhow!("Block {} cannot be both transparent and translucent", block.id));
+      }
+      blocks.add(block)?;
+    }
+
+    Ok(blocks)
+  }
+
+  fn add(&mut self, info: BlockInfo) -> Result<()> {
+    let block = Block(u16::try_from(self.blocks.len())?);
+    if let Some(existing) = self.keys.insert(info.key, block) {
+      return Err(anyhow!(
+        "Block {} has the same key ({:?}) as {}",
+        info.id,
+        info.key,
+        self.get(existing).id
+      ));
+    }
+
+    self.blocks.push(info);
+    Ok(())
+  }
+
   /// Parse a block from a character. Returns [`None`] when an invalid character
   /// is given.
   ///
   /// This is used when deserialising a world.
-  fn parse(c: char) -> Option<Block> {
-    match c {
-      ' ' => Some(Block::Air),
-      'd' => Some(Block::Dirt),
-      'g' => Some(Block::Grass),
-      's' => Some(Block::Stone),
-      'w' => Some(Block::Water),
-      _ => None,
+  pub fn parse(&self, c: char) -> Option<Block> {
+    self.keys.get(&c).copied()
+  }
+
+  /// Find a block from its Minecraft id, such as `minecraft:stone`.
+  pub fn find(&self, id: &str) -> Option<Block> {
+    self
+      .iter()
+      .find(|(_, info)| info.id == id)
+      .map(|(block, _)| block)
+  }
+
+  /// Get the definition of a block.
+  pub fn get(&self, block: Block) -> &BlockInfo {
+    &self.blocks[block.index()]
+  }
+
+  /// Check that this registry only adds blocks to the end of `old`, and so
+  /// every block in `old` still has the same index. Worlds store blocks by
+  /// their index, so this must hold for them to be used with this registry.
+  pub fn extends(&self, old: &Blocks) -> Result<()> {
+    if let Some((block, info)) = old.iter().skip(1).find(|(block, info)| {
+      self
+        .blocks
+        .get(block.index())
+        .is_none_or(|new| new.id != info.id || new.key != info.key)
+    }) {
+      return Err(anyhow!(
+        "Block {} ({:?}) was removed or moved from position {}. Blocks may only be added to the end of the registry",
+        info.id,
+        info.key,
+        block.index()
+      ));
     }
+
+    Ok(())
+  }
+
+  /// Iterate over all blocks in this registry.
+  pub fn iter(&self) -> impl Iterator<Item = (Block, &BlockInfo)> {
+    self
+      .blocks
+      .iter()
+      .enumerate()
+      .map(|(i, info)| (Block(i as u16), info))
+  }
+}
+
+/// The log2 of the size of a [`Section`] along each axis.
+const SECTION_BITS: usize = 4;
+/// The size of a [`Section`] along each axis.
+const SECTION_SIZE: usize = 1 << SECTION_BITS;
+const SECTION_MASK: usize = SECTION_SIZE - 1;
+const SECTION_VOLUME: usize = SECTION_SIZE * SECTION_SIZE * SECTION_SIZE;
+
+/// The log2 of the size of a brick (a smaller cube within a section) along
+/// each axis.
+const BRICK_BITS: usize = 2;
+const BRICK_SIZE: usize = 1 << BRICK_BITS;
+const BRICKS: usize = SECTION_SIZE / BRICK_SIZE;
+
+/// A 16x16x16 cube of blocks within a [`World`].
+///
+/// Most of a world is normally air (or solid ground), so sections where every
+/// block is the same are stored as that single block.
+#[derive(Clone)]
+enum Section {
+  Uniform(Block),
+  Blocks {
+    blocks: Box<[Block]>,
+    /// A bitset of which 4x4x4 bricks within this section only contain air.
+    /// This may be missing some bricks until [`World::compact`] is called.
+    empty: u64,
+    /// Whether this section has been edited since [`World::compact`] was last
+    /// called.
+    dirty: bool,
+  },
+}
+
+impl Section {
+  fn index(x: usize, y: usize, z: usize) -> usize {
+    (x & SECTION_MASK)
+      | (y & SECTION_MASK) << SECTION_BITS
+      | (z & SECTION_MASK) << (2 * SECTION_BITS)
+  }
+
+  fn brick(x: usize, y: usize, z: usize) -> u64 {
+    let brick = |x: usize| (x & SECTION_MASK) >> BRICK_BITS;
+    1 << (brick(x) + brick(y) * BRICKS + brick(z) * BRICKS * BRICKS)
   }
 }
 
 /// A world, containing a 3D grid of blocks.
+///
+/// Blocks are stored in sections of 16x16x16 blocks (see [`Section`]), so large
+/// worlds which are mostly empty take up much less memory. This also lets us
+/// skip over empty parts of the world when tracing rays (see
+/// [`World::get_cube`]).
+///
+/// Sections are shared between clones of a world, and only copied when one of
+/// them is edited. This keeps editing a copy of a large world cheap.
+#[derive(Clone)]
 pub struct World {
   pub width: usize,
   pub height: usize,
   pub depth: usize,
-  blocks: Vec<Block>,
+  /// The number of sections along the x and y axes.
+  sections_x: usize,
+  sections_y: usize,
+  sections: Vec<Arc<Section>>,
 }
 
 impl World {
   /// Construct a new world with the given dimensions. Blocks can then be
   /// modified with [`World::set`].
   pub fn new(width: usize, height: usize, depth: usize) -> World {
-    World { width, height, depth, blocks: vec![Block::Air; width * height * depth] }
+    let sections_x = (width + SECTION_MASK) >> SECTION_BITS;
+    let sections_y = (height + SECTION_MASK) >> SECTION_BITS;
+    let sections_z = (depth + SECTION_MASK) >> SECTION_BITS;
+    // Every section starts off sharing the same empty section, and is replaced
+    // when it is first edited.
+    let air = Arc::new(Section::Uniform(Block::AIR));
+    World {
+      width,
+      height,
+      depth,
+      sections_x,
+      sections_y,
+      sections: vec![air; sections_x * sections_y * sections_z],
+    }
+  }
+
+  fn section_index(&self, x: usize, y: usize, z: usize) -> usize {
+    (x >> SECTION_BITS)
+      + (y >> SECTION_BITS) * self.sections_x
+      + (z >> SECTION_BITS) * self.sections_x * self.sections_y
   }
 
   /// Get the block at the given position. Panics if the block is outside this
   /// world.
   pub fn get(&self, x: usize, y: usize, z: usize) -> Block {
     debug_assert!(x < self.width && y < self.height && z < self.depth);
-    self.blocks[x + y * self.width + z * self.height * self.width]
+    match &*self.sections[self.section_index(x, y, z)] {
+      Section::Uniform(block) => *block,
+      Section::Blocks { blocks, .. } => blocks[Section::index(x, y, z)],
+    }
+  }
+
+  /// Get the block at the given position, along with the size of the cube
+  /// around it which only contains that block. Panics if the block is outside
+  /// this world.
+  ///
+  /// Cubes are aligned to multiples of their size, and may extend past the
+  /// edges of the world. This is used to skip over large areas of empty space
+  /// when tracing rays.
+  pub fn get_cube(&self, x: usize, y: usize, z: usize) -> (Block, usize) {
+    debug_assert!(x < self.width && y < self.height && z < self.depth);
+    match &*self.sections[self.section_index(x, y, z)] {
+      Section::Uniform(block) => (*block, SECTION_SIZE),
+      Section::Blocks { empty, .. } if empty & Section::brick(x, y, z) != 0 => {
+        (Block::AIR, BRICK_SIZE)
+      }
+      Section::Blocks { blocks, .. } => (blocks[Section::index(x, y, z)], 1),
+    }
   }
 
   /// Set the block at the given position. Panics if the block is outside this
   /// world.
+  ///
+  /// This does not merge sections which become uniform, so should be followed
+  /// by [`World::compact`] after making lots of changes.
   pub fn set(&mut self, x: usize, y: usize, z: usize, block: Block) {
     debug_assert!(x < self.width && y < self.height && z < self.depth);
-    self.blocks[x + y * self.width + z * self.height * self.width] = block;
+    let index = self.section_index(x, y, z);
+    let section = &mut self.sections[index];
+    match &**section {
+      Section::Uniform(existing) if *existing == block => {}
+      Section::Uniform(existing) => {
+        let mut blocks = vec![*existing; SECTION_VOLUME].into_boxed_slice();
+        blocks[Section::index(x, y, z)] = block;
+        let empty = if *existing == Block::AIR {
+          !Section::brick(x, y, z)
+        } else {
+          0
+        };
+        *section = Arc::new(Section::Blocks { blocks, empty, dirty: true });
+      }
+      Section::Blocks { blocks, .. } if blocks[Section::index(x, y, z)] == block => {}
+      Section::Blocks { .. } => {
+        if let Section::Blocks { blocks, empty, dirty } = Arc::make_mut(section) {
+          blocks[Section::index(x, y, z)] = block;
+          if block != Block::AIR {
+            *empty &= !Section::brick(x, y, z);
+          }
+          *dirty = true;
+        }
+      }
+    }
+  }
+
+  /// Store any sections where every block is the same as that single block,
+  /// and find which bricks in the remaining sections are empty.
+  ///
+  /// Only sections which have been edited since this was last called are
+  /// checked.
+  pub fn compact(&mut self) {
+    for section in &mut self.sections {
+      if !matches!(**section, Section::Blocks { dirty: true, .. }) {
+        continue;
+      }
+      let (blocks, empty, dirty) = match Arc::make_mut(section) {
+        Section::Uniform(_) => continue,
+        Section::Blocks { blocks, empty, dirty } => (blocks, empty, dirty),
+      };
+      *dirty = false;
+
+      let first = blocks[0];
+      if blocks.iter().all(|block| *block == first) {
+        *section = Arc::new(Section::Uniform(first));
+        continue;
+      }
+
+      // Recompute the empty bricks from scratch, as setting a block to air
+      // doesn't mark its brick as empty.
+      *empty = !0;
+      for (i, block) in blocks.iter().enumerate() {
+        if *block != Block::AIR {
+          let (x, y, z) = (i, i >> SECTION_BITS, i >> (2 * SECTION_BITS));
+          *empty &= !Section::brick(x, y, z);
+        }
+      }
+    }
+  }
+
+  /// The approximate amount of memory used by this world's blocks, in bytes.
+  pub fn memory_usage(&self) -> usize {
+    self.sections.len() * std::mem::size_of::<Section>()
+      + self
+        .sections
+        .iter()
+        .filter(|section| matches!(***section, Section::Blocks { .. }))
+        .count()
+        * SECTION_VOLUME
+        * std::mem::size_of::<Block>()
   }
 }
 
-impl<'de> Deserialize<'de> for World {
-  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-  where
-    D: Deserializer<'de>,
-  {
-    let contents: Vec<Vec<String>> = Deserialize::deserialize(deserializer)?;
+/// The serialised form of a [`World`]. This is a list of horizontal slices of
+/// the world (from lowest to highest), each of which is a list of rows, with
+/// one character per block.
+pub type Grid = Vec<Vec<String>>;
 
-    let width = contents[0][0].len();
+/// A change to part of a [`World`], applied with [`World::apply`].
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum Edit {
+  /// Replace a single block.
+  Block {
+    x: usize,
+    y: usize,
+    z: usize,
+    block: char,
+  },
+  /// Replace a cuboid of blocks, whose lowest corner is at the given position.
+  Region {
+    x: usize,
+    y: usize,
+    z: usize,
+    world: Grid,
+  },
+}
+
+/// An error which occurred when building a world from its serialised form, or
+/// applying edits to it.
+#[derive(Debug)]
+pub enum WorldError {
+  /// The world contains no blocks.
+  Empty,
+  /// A layer has a different number of rows to the first layer.
+  RaggedLayer {
+    layer: usize,
+    expected: usize,
+    actual: usize,
+  },
+  /// A row has a different number of blocks to the first row.
+  RaggedRow {
+    layer: usize,
+    row: usize,
+    expected: usize,
+    actual: usize,
+  },
+  /// The world contains more blocks than we allow.
+  TooLarge {
+    volume: Option<usize>,
+    max_volume: usize,
+  },
+  /// The world contains a block which isn't in the registry.
+  UnknownBlock {
+    block: char,
+    x: usize,
+    y: usize,
+    z: usize,
+  },
+  /// A binary world could not be decoded (see [`World::from_binary`]).
+  Malformed(&'static str),
+  /// An edit extends outside the world.
+  OutOfBounds {
+    x: usize,
+    y: usize,
+    z: usize,
+    width: usize,
+    height: usize,
+    depth: usize,
+  },
+}
+
+impl std::fmt::Display for WorldError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      WorldError::Empty => write!(f, "World is empty"),
+      WorldError::RaggedLayer { layer, expected, actual } => {
+        write!(f, "Layer {} has {} rows, but should have {}", layer, actual, expected)
+      }
+      WorldError::RaggedRow { layer, row, expected, actual } => write!(
+        f,
+        "Row {} of layer {} has {} blocks, but should have {}",
+        row, layer, actual, expected
+      ),
+      WorldError::TooLarge { volume: Some(volume), max_volume } => {
+        write!(f, "World has {} blocks, but can have at most {}", volume, max_volume)
+      }
+      WorldError::TooLarge { volume: None, max_volume } => {
+        write!(f, "World is too large (can have at most {} blocks)", max_volume)
+      }
+      WorldError::UnknownBlock { block, x, y, z } => {
+        write!(f, "Unknown block {:?} at {}, {}, {}", block, x, y, z)
+      }
+      WorldError::Malformed(message) => write!(f, "Invalid binary world: {}", message),
+      WorldError::OutOfBounds { x, y, z, width, height, depth } => write!(
+        f,
+        "Edit at {}, {}, {} is outside the world (which is {}x{}x{})",
+        x, y, z, width, height, depth
+      ),
+    }
+  }
+}
+
+impl std::error::Error for WorldError {}
+
+/// Check a world's dimensions are non-zero and its volume is within the limit,
+/// returning that volume.
+pub fn check_size(
+  width: usize,
+  height: usize,
+  depth: usize,
+  max_volume: usize,
+) -> Result<usize, WorldError> {
+  if width == 0 || height == 0 || depth == 0 {
+    return Err(WorldError::Empty);
+  }
+
+  let volume = width.checked_mul(height).and_then(|x| x.checked_mul(depth));
+  match volume {
+    Some(volume) if volume <= max_volume => Ok(volume),
+    _ => Err(WorldError::TooLarge { volume, max_volume }),
+  }
+}
+
+impl World {
+  /// Build a world from its serialised form, looking up each block in the
+  /// registry.
+  ///
+  /// The world must be non-empty, every layer must have the same number of rows,
+  /// and every row must have the same number of blocks. Worlds with more than
+  /// `max_volume` blocks are rejected.
+  pub fn from_grid(
+    contents: &Grid,
+    blocks: &Blocks,
+    max_volume: usize,
+  ) -> Result<World, WorldError> {
     let height = contents.len();
-    let depth = contents[0].len();
+    let depth = contents.first().map_or(0, |layer| layer.len());
+    let width = contents
+      .first()
+      .and_then(|layer| layer.first())
+      .map_or(0, |row| row.chars().count());
+    check_size(width, height, depth, max_volume)?;
 
     let mut world = World::new(width, height, depth);
 
     for (y, plane) in contents.iter().enumerate() {
+      if plane.len() != depth {
+        return Err(WorldError::RaggedLayer { layer: y, expected: depth, actual: plane.len() });
+      }
+
       for (z, row) in plane.iter().enumerate() {
+        let actual = row.chars().count();
+        if actual != width {
+          return Err(WorldError::RaggedRow { layer: y, row: z, expected: width, actual });
+        }
+
         for (x, cell) in row.chars().enumerate() {
-          match Block::parse(cell) {
-            None => return Err(D::Error::custom(format!("Unknown block {}", cell))),
+          match blocks.parse(cell) {
+            None => return Err(WorldError::UnknownBlock { block: cell, x, y, z }),
             Some(block) => world.set(x, y, z, block),
           }
         }
       }
     }
 
+    world.compact();
+    Ok(world)
+  }
+
+  /// Build a world from its compact binary form, looking up each block in the
+  /// registry. This is much smaller than a [`Grid`] for worlds with lots of air,
+  /// and cheaper to produce in Lua. It consists of:
+  ///
+  ///  - The world's width, height and depth, each as a big-endian `u16`.
+  ///  - A palette of blocks: a single byte `n`, followed by `n` bytes of UTF-8
+  ///    containing the key of each block.
+  ///  - A list of runs, each two bytes long: `n` and `i`, meaning the next
+  ///    `n + 1` blocks are the `i`th block in the palette.
+  ///
+  /// Blocks are stored in the same order as a [`Grid`]: x varies fastest, then
+  /// z, then y.
+  pub fn from_binary(data: &[u8], blocks: &Blocks, max_volume: usize) -> Result<World, WorldError> {
+    if data.len() < 7 {
+      return Err(WorldError::Malformed("Missing header"));
+    }
+
+    let dimension = |i: usize| u16::from_be_bytes([data[i], data[i + 1]]) as usize;
+    let (width, height, depth) = (dimension(0), dimension(2), dimension(4));
+    let volume = check_size(width, height, depth, max_volume)?;
+
+    let palette_end = 7 + data[6] as usize;
+    let palette = data
+      .get(7..palette_end)
+      .ok_or(WorldError::Malformed("Palette is truncated"))?;
+    let palette: Vec<char> = std::str::from_utf8(palette)
+      .map_err(|_| WorldError::Malformed("Palette is not valid UTF-8"))?
+      .chars()
+      .collect();
+
+    let mut world = World::new(width, height, depth);
+    let mut position = 0;
+    for run in data[palette_end..].chunks(2) {
+      let (length, index) = match run {
+        [length, index] => (*length as usize + 1, *index as usize),
+        _ => return Err(WorldError::Malformed("Run is truncated")),
+      };
+      let key = *palette
+        .get(index)
+        .ok_or(WorldError::Malformed("Palette index out of range"))?;
+      if length > volume - position {
+        return Err(WorldError::Malformed("Runs extend past the end of the world"));
+      }
+
+      let (x, z, y) = (position % width, (position / width) % depth, position / (width * depth));
+      let block = blocks
+        .parse(key)
+        .ok_or(WorldError::UnknownBlock { block: key, x, y, z })?;
+      for position in position..position + length {
+        let (x, z, y) = (position % width, (position / width) % depth, position / (width * depth));
+        world.set(x, y, z, block);
+      }
+      position += length;
+    }
+
+    if position != volume {
+      return Err(WorldError::Malformed("Runs do not fill the world"));
+    }
+
+    world.compact();
     Ok(world)
   }
+
+  /// Convert this world to its serialised form (see [`World::from_grid`]).
+  pub fn to_grid(&self, blocks: &Blocks) -> Grid {
+    (0..self.height)
+      .map(|y| {
+        (0..self.depth)
+          .map(|z| {
+            (0..self.width)
+              .map(|x| blocks.get(self.get(x, y, z)).key)
+              .collect()
+          })
+          .collect()
+      })
+      .collect()
+  }
+
+  /// Convert this world to its compact binary form (see
+  /// [`World::from_binary`]).
+  pub fn to_binary(&self, blocks: &Blocks) -> Result<Vec<u8>> {
+    let dimension = |size: usize| {
+      u16::try_from(size).map_err(|_| anyhow!("World is too large ({} blocks wide)", size))
+    };
+
+    let mut out = Vec::new();
+    for size in [self.width, self.height, self.depth] {
+      out.extend_from_slice(&dimension(size)?.to_be_bytes());
+    }
+
+    let mut palette: Vec<Block> = Vec::new();
+    let mut runs: Vec<u8> = Vec::new();
+    let mut run: Option<(Block, usize)> = None;
+    let mut flush = |block: Block, length: usize| -> Result<()> {
+      let index = match palette.iter().position(|x| *x == block) {
+        Some(index) => index,
+        None => {
+          palette.push(block);
+          palette.len() - 1
+        }
+      };
+      let index = u8::try_from(index).map_err(|_| anyhow!("World has too many blocks"))?;
+      runs.push((length - 1) as u8);
+      runs.push(index);
+      Ok(())
+    };
+
+    for y in 0..self.height {
+      for z in 0..self.depth {
+        for x in 0..self.width {
+          let block = self.get(x, y, z);
+          run = match run {
+            Some((current, length)) if current == block && length < 256 => {
+              Some((block, length + 1))
+            }
+            Some((current, length)) => {
+              flush(current, length)?;
+              Some((block, 1))
+            }
+            None => Some((block, 1)),
+          };
+        }
+      }
+    }
+    if let Some((current, length)) = run {
+      flush(current, length)?;
+    }
+
+    let keys: String = palette.iter().map(|block| blocks.get(*block).key).collect();
+    let keys_len = u8::try_from(keys.len()).map_err(|_| anyhow!("World has too many blocks"))?;
+    out.push(keys_len);
+    out.extend_from_slice(keys.as_bytes());
+    out.extend_from_slice(&runs);
+    Ok(out)
+  }
+
+  /// Apply a list of edits to this world.
+  ///
+  /// Every edit is checked before any are applied, so if an error is returned
+  /// the world is left unchanged.
+  pub fn apply(&mut self, edits: &[Edit], blocks: &Blocks) -> Result<(), WorldError> {
+    /// An edit which has been checked, and is ready to be applied.
+    enum Change {
+      Block(Block),
+      Region(World),
+    }
+
+    let mut changes = Vec::with_capacity(edits.len());
+    for edit in edits {
+      let (x, y, z, change) = match edit {
+        Edit::Block { x, y, z, block } => {
+          let block = blocks.parse(*block).ok_or(WorldError::UnknownBlock {
+            block: *block,
+            x: *x,
+            y: *y,
+            z: *z,
+          })?;
+          (*x, *y, *z, Change::Block(block))
+        }
+        Edit::Region { x, y, z, world } => {
+          let region = World::from_grid(world, blocks, self.width * self.height * self.depth)
+            .map_err(|err| match err {
+              WorldError::UnknownBlock { block, x: dx, y: dy, z: dz } => WorldError::UnknownBlock {
+                block,
+                x: x.saturating_add(dx),
+                y: y.saturating_add(dy),
+                z: z.saturating_add(dz),
+              },
+              err => err,
+            })?;
+          (*x, *y, *z, Change::Region(region))
+        }
+      };
+
+      let (width, height, depth) = match &change {
+        Change::Block(_) => (1, 1, 1),
+        Change::Region(region) => (region.width, region.height, region.depth),
+      };
+      if x >= self.width
+        || y >= self.height
+        || z >= self.depth
+        || width > self.width - x
+        || height > self.height - y
+        || depth > self.depth - z
+      {
+        return Err(WorldError::OutOfBounds {
+          x,
+          y,
+          z,
+          width: self.width,
+          height: self.height,
+          depth: self.depth,
+        });
+      }
+
+      changes.push((x, y, z, change));
+    }
+
+    for (x, y, z, change) in changes {
+      match change {
+        Change::Block(block) => self.set(x, y, z, block),
+        Change::Region(region) => {
+          for dz in 0..region.depth {
+            for dy in 0..region.height {
+              for dx in 0..region.width {
+                self.set(x + dx, y + dy, z + dz, region.get(dx, dy, dz));
+              }
+            }
+          }
+        }
+      }
+    }
+
+    self.compact();
+    Ok(())
+  }
 }